@@ -0,0 +1,256 @@
+//! Angle sensors, and conversion of their readings to electrical angle.
+//!
+//! Any sensor that reports a position as a count within a revolution can
+//! implement [`AngleSensor`], including absolute and incremental encoders,
+//! Hall sensors (with six counts per electrical revolution), and observers.
+//! [`AngleAdapter`] then takes care of the pole pairs, direction, and
+//! electrical offset to produce the `angle` argument of
+//! [`Foc::update`](crate::Foc::update).
+
+use fixed::types::I16F16;
+
+/// A sensor which measures the angular position of the rotor.
+pub trait AngleSensor {
+    type Error;
+
+    /// The number of counts in one revolution of the sensor.
+    ///
+    /// For sensors that measure the electrical angle directly (e.g. Hall
+    /// sensors or observers) this is the number of counts in an electrical
+    /// revolution, and the adapter should be configured with one pole pair.
+    fn counts_per_revolution(&self) -> u32;
+
+    /// Read the position of the sensor, in counts between 0 and
+    /// [`AngleSensor::counts_per_revolution`] (exclusive).
+    fn read_counts(&mut self) -> Result<u32, Self::Error>;
+}
+
+/// Wrap an angle in radians to `[0, 2π)`.
+pub fn wrap(angle: I16F16) -> I16F16 {
+    angle.rem_euclid(I16F16::TAU)
+}
+
+/// The shortest signed difference `a - b` between two angles in radians,
+/// in `[-π, π)`.
+pub fn difference(a: I16F16, b: I16F16) -> I16F16 {
+    wrap(a.wrapping_sub(b).wrapping_add(I16F16::PI)) - I16F16::PI
+}
+
+/// The angle of the vector `(x, y)` in radians, in `[-π, π]`.
+///
+/// Unlike [`cordic::atan2`], this does not overflow when `x` is small
+/// compared to `y`.
+pub fn atan2(y: I16F16, x: I16F16) -> I16F16 {
+    let abs_x = x.saturating_abs();
+    let abs_y = y.saturating_abs();
+    if abs_x == I16F16::ZERO && abs_y == I16F16::ZERO {
+        return I16F16::ZERO;
+    }
+
+    let first_quadrant = if abs_y <= abs_x {
+        cordic::atan(abs_y / abs_x)
+    } else {
+        I16F16::FRAC_PI_2 - cordic::atan(abs_x / abs_y)
+    };
+
+    match (x.is_negative(), y.is_negative()) {
+        (false, false) => first_quadrant,
+        (true, false) => I16F16::PI - first_quadrant,
+        (true, true) => first_quadrant - I16F16::PI,
+        (false, true) => -first_quadrant,
+    }
+}
+
+/// A single reading from an [`AngleAdapter`].
+#[derive(Debug, Clone)]
+pub struct AngleReading {
+    /// Electrical angle in radians, in `[0, 2π)`.
+    pub electrical_angle: I16F16,
+    /// Mechanical angle in radians, in `[0, 2π)`.
+    pub mechanical_angle: I16F16,
+    /// Multi-turn mechanical position in radians, relative to the position at
+    /// the first reading plus its mechanical angle. Saturates when out of range.
+    pub position: I16F16,
+    /// Mechanical velocity in rad/s.
+    pub velocity: I16F16,
+}
+
+/// Converts the raw counts of an [`AngleSensor`] into electrical angle,
+/// mechanical position and velocity.
+pub struct AngleAdapter<S: AngleSensor> {
+    sensor: S,
+    pole_pairs: u8,
+    inverted: bool,
+    electrical_offset: I16F16,
+    last_counts: Option<u32>,
+    total_counts: i64,
+}
+
+impl<S: AngleSensor> AngleAdapter<S> {
+    /// Create a new adapter for a sensor on a motor with the given number of
+    /// pole pairs.
+    pub fn new(sensor: S, pole_pairs: u8) -> Self {
+        Self {
+            sensor,
+            pole_pairs,
+            inverted: false,
+            electrical_offset: I16F16::ZERO,
+            last_counts: None,
+            total_counts: 0,
+        }
+    }
+
+    /// Invert the direction of the sensor, e.g. if it is mounted on the
+    /// opposite side of the motor or the phase order is reversed.
+    pub fn with_inverted(mut self, inverted: bool) -> Self {
+        self.inverted = inverted;
+        self
+    }
+
+    /// Set the electrical offset in radians, i.e. the electrical angle of the
+    /// sensor when the rotor is aligned with the phase A axis.
+    pub fn with_electrical_offset(mut self, electrical_offset: I16F16) -> Self {
+        self.set_electrical_offset(electrical_offset);
+        self
+    }
+
+    /// Change the electrical offset in radians, e.g. after calibrating the
+    /// sensor.
+    pub fn set_electrical_offset(&mut self, electrical_offset: I16F16) {
+        self.electrical_offset = wrap(electrical_offset);
+    }
+
+    /// The underlying sensor.
+    pub fn sensor(&self) -> &S {
+        &self.sensor
+    }
+
+    /// The underlying sensor.
+    pub fn sensor_mut(&mut self) -> &mut S {
+        &mut self.sensor
+    }
+
+    /// Read the sensor, returning the new angle and velocity.
+    ///
+    /// `dt` is the time since the last update in seconds, and is used to
+    /// estimate the velocity. The velocity of the first reading is zero.
+    pub fn update(&mut self, dt: I16F16) -> Result<AngleReading, S::Error> {
+        let cpr = self.sensor.counts_per_revolution().max(1);
+        let raw_counts = self.sensor.read_counts()? % cpr;
+        let counts = if self.inverted {
+            (cpr - raw_counts) % cpr
+        } else {
+            raw_counts
+        };
+
+        // Change in counts since the last reading, assuming that the sensor
+        // moved less than half a revolution
+        let delta = match self.last_counts {
+            Some(last) => {
+                let delta = (i64::from(counts) - i64::from(last)).rem_euclid(i64::from(cpr));
+                if delta >= i64::from(cpr.div_ceil(2)) {
+                    delta - i64::from(cpr)
+                } else {
+                    delta
+                }
+            }
+            None => {
+                self.total_counts = i64::from(counts);
+                0
+            }
+        };
+        self.last_counts = Some(counts);
+        self.total_counts += delta;
+
+        let electrical_counts = (u64::from(counts) * u64::from(self.pole_pairs)) % u64::from(cpr);
+        let electrical_angle = wrap(
+            counts_to_angle(electrical_counts as i64, cpr).wrapping_sub(self.electrical_offset),
+        );
+
+        let velocity = if dt > I16F16::ZERO {
+            counts_to_angle(delta, cpr).saturating_div(dt)
+        } else {
+            I16F16::ZERO
+        };
+
+        Ok(AngleReading {
+            electrical_angle,
+            mechanical_angle: counts_to_angle(i64::from(counts), cpr),
+            position: counts_to_angle(self.total_counts, cpr),
+            velocity,
+        })
+    }
+}
+
+/// Convert a number of counts to an angle in radians, saturating if out of
+/// range.
+fn counts_to_angle(counts: i64, counts_per_revolution: u32) -> I16F16 {
+    let bits =
+        i128::from(counts) * i128::from(I16F16::TAU.to_bits()) / i128::from(counts_per_revolution);
+    I16F16::from_bits(bits.clamp(i32::MIN.into(), i32::MAX.into()) as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FakeSensor(u32);
+
+    impl AngleSensor for FakeSensor {
+        type Error = ();
+
+        fn counts_per_revolution(&self) -> u32 {
+            4096
+        }
+
+        fn read_counts(&mut self) -> Result<u32, Self::Error> {
+            Ok(self.0)
+        }
+    }
+
+    #[test]
+    fn angle_difference_wraps() {
+        let diff = difference(I16F16::lit("0.1"), I16F16::lit("6.2"));
+        assert!(diff.abs_diff(I16F16::lit("0.1") + I16F16::TAU - I16F16::lit("6.2")) < 0.0001);
+        let diff = difference(I16F16::lit("6.2"), I16F16::lit("0.1"));
+        assert!(diff.abs_diff(I16F16::lit("6.1") - I16F16::TAU) < 0.0001);
+    }
+
+    #[test]
+    fn atan2_quadrants() {
+        for step in 0..62 {
+            let angle = -3.1 + step as f32 * 0.1;
+            let (y, x) = (0.01 * angle.sin(), 0.01 * angle.cos());
+            let result = atan2(I16F16::from_num(y), I16F16::from_num(x));
+            assert!(result.abs_diff(I16F16::from_num(angle)) < 0.005);
+        }
+        assert!(atan2(I16F16::from_num(1000), I16F16::DELTA).abs_diff(I16F16::FRAC_PI_2) < 0.001);
+    }
+
+    #[test]
+    fn electrical_angle() {
+        let mut adapter =
+            AngleAdapter::new(FakeSensor(1024), 7).with_electrical_offset(I16F16::FRAC_PI_2);
+
+        // A quarter turn mechanically is 7 quarter turns electrically
+        let reading = adapter.update(I16F16::ONE).unwrap();
+        assert!(reading.mechanical_angle.abs_diff(I16F16::FRAC_PI_2) < 0.001);
+        assert!(reading.electrical_angle.abs_diff(I16F16::PI) < 0.001);
+    }
+
+    #[test]
+    fn velocity_and_position_across_wrap() {
+        let mut adapter = AngleAdapter::new(FakeSensor(100), 1).with_inverted(true);
+        adapter.update(I16F16::lit("0.5")).unwrap();
+
+        // Inverted, so the sensor counting down is a positive velocity
+        adapter.sensor_mut().0 = 4000;
+        let reading = adapter.update(I16F16::lit("0.5")).unwrap();
+        assert!(reading.velocity.abs_diff(I16F16::TAU * 392 / 4096) < 0.001);
+        assert!(reading.position.abs_diff(I16F16::TAU * 4192 / 4096) < 0.001);
+
+        adapter.sensor_mut().0 = 200;
+        let reading = adapter.update(I16F16::lit("0.5")).unwrap();
+        assert!(reading.velocity.abs_diff(-I16F16::TAU * 592 / 4096) < 0.001);
+    }
+}
@@ -0,0 +1,302 @@
+//! Conversion of raw ADC readings into phase currents.
+//!
+//! Two layouts of current sensors are supported:
+//! - Two sensors, on phases A and B ([`CurrentSense<2>`]). This covers both
+//!   inline sensors, which can be sampled at any time, and low-side shunts,
+//!   which must be sampled while the low-side switches are on (i.e. at the
+//!   centre of a centre-aligned PWM period).
+//! - Three low-side shunts, one on each phase ([`CurrentSense<3>`]). When
+//!   used with [`SpaceVector`] modulation the phase with the widest high-side
+//!   duty can be ignored, see [`reconstruct_three_shunt`].
+//!
+//! In both cases the output is the [`ThreePhaseBalancedReferenceFrame`]
+//! expected by [`park_clarke::clarke`](crate::park_clarke::clarke).
+
+use fixed::types::I16F16;
+
+use crate::{
+    park_clarke::ThreePhaseBalancedReferenceFrame,
+    pwm::{Sector, SpaceVector},
+};
+
+/// Conversion from raw ADC counts to amps for a single channel.
+#[derive(Debug, Clone)]
+pub struct AdcConversion {
+    full_scale_current: I16F16,
+    resolution_bits: u8,
+    offset: u16,
+}
+
+impl AdcConversion {
+    /// Create a new conversion, where a change of the full ADC range
+    /// corresponds to `full_scale_current` amps.
+    ///
+    /// The zero-current offset defaults to the middle of the ADC range. A
+    /// negative `full_scale_current` can be used if the reading decreases as
+    /// the current increases. The resolution is clamped to between 1 and 16
+    /// bits.
+    pub fn new(full_scale_current: I16F16, resolution_bits: u8) -> Self {
+        let resolution_bits = resolution_bits.clamp(1, 16);
+        Self {
+            full_scale_current,
+            resolution_bits,
+            offset: ((1u32 << resolution_bits) / 2) as u16,
+        }
+    }
+
+    /// Create a new conversion for a shunt resistor (in ohms) followed by an
+    /// amplifier with the given gain, read by an ADC with the given reference
+    /// voltage (in volts) and resolution.
+    pub fn from_shunt(
+        shunt_resistance: I16F16,
+        amplifier_gain: I16F16,
+        reference_voltage: I16F16,
+        resolution_bits: u8,
+    ) -> Self {
+        Self::new(
+            reference_voltage / (shunt_resistance * amplifier_gain),
+            resolution_bits,
+        )
+    }
+
+    /// Set the ADC reading which corresponds to zero current.
+    pub fn with_offset(mut self, offset: u16) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    /// The ADC reading which corresponds to zero current.
+    pub fn offset(&self) -> u16 {
+        self.offset
+    }
+
+    /// Convert a raw ADC reading into amps.
+    pub fn convert(&self, raw: u16) -> I16F16 {
+        let counts = i64::from(raw) - i64::from(self.offset);
+        let bits = (counts * i64::from(self.full_scale_current.to_bits())) >> self.resolution_bits;
+        I16F16::from_bits(bits.clamp(i32::MIN.into(), i32::MAX.into()) as i32)
+    }
+}
+
+/// Current sensing for a set of `CHANNELS` phase current sensors, which
+/// converts raw ADC readings into a balanced three-phase current.
+#[derive(Debug, Clone)]
+pub struct CurrentSense<const CHANNELS: usize> {
+    conversions: [AdcConversion; CHANNELS],
+}
+
+impl<const CHANNELS: usize> CurrentSense<CHANNELS> {
+    /// Create a new current sense, with the same conversion for each channel.
+    pub fn new(conversion: AdcConversion) -> Self {
+        Self {
+            conversions: core::array::from_fn(|_| conversion.clone()),
+        }
+    }
+
+    /// Set the zero-current offset of each channel, e.g. from the result of
+    /// an [`OffsetCalibration`].
+    pub fn set_offsets(&mut self, offsets: [u16; CHANNELS]) {
+        for (conversion, offset) in self.conversions.iter_mut().zip(offsets) {
+            conversion.offset = offset;
+        }
+    }
+
+    /// The zero-current offset of each channel.
+    pub fn offsets(&self) -> [u16; CHANNELS] {
+        core::array::from_fn(|i| self.conversions[i].offset)
+    }
+
+    /// The phase currents in amps, in the order of the channels.
+    pub fn phase_currents(&self, raw: [u16; CHANNELS]) -> [I16F16; CHANNELS] {
+        core::array::from_fn(|i| self.conversions[i].convert(raw[i]))
+    }
+}
+
+impl CurrentSense<2> {
+    /// Convert the raw readings of the sensors on phases A and B.
+    pub fn convert(&self, raw: [u16; 2]) -> ThreePhaseBalancedReferenceFrame {
+        let [a, b] = self.phase_currents(raw);
+        ThreePhaseBalancedReferenceFrame { a, b }
+    }
+}
+
+impl CurrentSense<3> {
+    /// Convert the raw readings of the sensors on phases A, B and C.
+    ///
+    /// Any common-mode error between the three readings is removed.
+    pub fn convert(&self, raw: [u16; 3]) -> ThreePhaseBalancedReferenceFrame {
+        let [a, b, c] = self.phase_currents(raw);
+        let common_mode = (a.saturating_add(b).saturating_add(c)) / 3;
+        ThreePhaseBalancedReferenceFrame {
+            a: a.saturating_sub(common_mode),
+            b: b.saturating_sub(common_mode),
+        }
+    }
+
+    /// Convert the raw readings of the sensors on phases A, B and C, ignoring
+    /// the phase which cannot be reliably sampled in the given space-vector
+    /// sector.
+    pub fn convert_in_sector(
+        &self,
+        raw: [u16; 3],
+        sector: Sector,
+    ) -> ThreePhaseBalancedReferenceFrame {
+        reconstruct_three_shunt(self.phase_currents(raw), sector)
+    }
+}
+
+/// Conversion of raw readings into a balanced three-phase current, for code
+/// which is generic over the number of channels.
+///
+/// This is implemented for [`CurrentSense<2>`] and [`CurrentSense<3>`], and
+/// calls their `convert` methods.
+pub trait ConvertCurrents<const CHANNELS: usize> {
+    /// Convert the raw readings of each channel.
+    fn convert(&self, raw: [u16; CHANNELS]) -> ThreePhaseBalancedReferenceFrame;
+}
+
+impl ConvertCurrents<2> for CurrentSense<2> {
+    fn convert(&self, raw: [u16; 2]) -> ThreePhaseBalancedReferenceFrame {
+        CurrentSense::<2>::convert(self, raw)
+    }
+}
+
+impl ConvertCurrents<3> for CurrentSense<3> {
+    fn convert(&self, raw: [u16; 3]) -> ThreePhaseBalancedReferenceFrame {
+        CurrentSense::<3>::convert(self, raw)
+    }
+}
+
+/// Rebuild the balanced three-phase current from the currents measured by
+/// three low-side shunts, using only the two phases that can be reliably
+/// sampled in the given space-vector sector.
+///
+/// The phase with the widest high-side duty (see
+/// [`SpaceVector::highest_duty_phase`]) has the shortest low-side on-time, so
+/// its current is calculated from the other two instead.
+pub fn reconstruct_three_shunt(
+    currents: [I16F16; 3],
+    sector: Sector,
+) -> ThreePhaseBalancedReferenceFrame {
+    let [a, b, c] = currents;
+    match SpaceVector::highest_duty_phase(sector) {
+        0 => ThreePhaseBalancedReferenceFrame {
+            a: -(b.saturating_add(c)),
+            b,
+        },
+        1 => ThreePhaseBalancedReferenceFrame {
+            a,
+            b: -(a.saturating_add(c)),
+        },
+        _ => ThreePhaseBalancedReferenceFrame { a, b },
+    }
+}
+
+/// Calibration of the zero-current offsets of a set of current sensors.
+///
+/// Samples should be taken while the PWM outputs are disabled, so that no
+/// current flows through the motor. The offset of each channel is the average
+/// of the samples.
+#[derive(Debug, Clone)]
+pub struct OffsetCalibration<const CHANNELS: usize> {
+    samples: u16,
+    count: u16,
+    sums: [u32; CHANNELS],
+}
+
+impl<const CHANNELS: usize> OffsetCalibration<CHANNELS> {
+    /// Create a new calibration which averages the given number of samples.
+    pub fn new(samples: u16) -> Self {
+        Self {
+            samples: samples.max(1),
+            count: 0,
+            sums: [0; CHANNELS],
+        }
+    }
+
+    /// Add a set of raw readings to the calibration.
+    ///
+    /// Returns the offsets once enough samples have been collected, after
+    /// which the calibration restarts.
+    pub fn add_sample(&mut self, raw: [u16; CHANNELS]) -> Option<[u16; CHANNELS]> {
+        for (sum, raw) in self.sums.iter_mut().zip(raw) {
+            *sum += u32::from(raw);
+        }
+        self.count += 1;
+
+        if self.count < self.samples {
+            return None;
+        }
+
+        let count = u32::from(self.count);
+        let offsets = self.sums.map(|sum| ((sum + count / 2) / count) as u16);
+        self.count = 0;
+        self.sums = [0; CHANNELS];
+        Some(offsets)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shunt_conversion() {
+        // 10 mOhm shunt, gain of 20, 3.3V 12-bit ADC
+        let conversion = AdcConversion::from_shunt(
+            I16F16::lit("0.01"),
+            I16F16::from_num(20),
+            I16F16::lit("3.3"),
+            12,
+        );
+        assert_eq!(conversion.offset(), 2048);
+
+        // 1 A => 0.2 V => 248.2 counts
+        let current = conversion.convert(2048 + 248);
+        assert!(current.abs_diff(I16F16::ONE) < 0.01);
+        let current = conversion.convert(2048 - 1986);
+        assert!(current.abs_diff(I16F16::from_num(-8)) < 0.01);
+
+        // Out of range resolutions are clamped
+        let conversion = AdcConversion::new(I16F16::from_num(64), 200);
+        assert_eq!(conversion.offset(), 32768);
+        assert_eq!(conversion.convert(32768 + 1024), 1);
+    }
+
+    #[test]
+    fn calibrated_three_shunt() {
+        let mut sense = CurrentSense::<3>::new(AdcConversion::new(I16F16::from_num(64), 12));
+        let mut calibration = OffsetCalibration::new(4);
+
+        for raw in [[2000, 2100, 1990], [2002, 2101, 1991], [2000, 2099, 1990]] {
+            assert!(calibration.add_sample(raw).is_none());
+        }
+        let offsets = calibration.add_sample([2002, 2100, 1989]);
+        assert_eq!(offsets, Some([2001, 2100, 1990]));
+        sense.set_offsets(offsets.unwrap());
+
+        // 1 A is 64 counts
+        let currents = sense.convert([2001 + 64, 2100 - 32, 1990 - 32]);
+        assert_eq!(currents.a, 1);
+        assert_eq!(currents.b, -0.5);
+    }
+
+    #[test]
+    fn three_shunt_reconstruction() {
+        // The unreliable phase reads zero
+        let currents = [I16F16::ZERO, I16F16::ONE, I16F16::from_num(-3)];
+        let reconstructed = reconstruct_three_shunt(currents, Sector::One);
+        assert_eq!(reconstructed.a, 2);
+        assert_eq!(reconstructed.b, 1);
+
+        let currents = [I16F16::from_num(2), I16F16::ZERO, I16F16::from_num(-3)];
+        let reconstructed = reconstruct_three_shunt(currents, Sector::Three);
+        assert_eq!(reconstructed.a, 2);
+        assert_eq!(reconstructed.b, 1);
+
+        let currents = [I16F16::from_num(2), I16F16::ONE, I16F16::ZERO];
+        let reconstructed = reconstruct_three_shunt(currents, Sector::Five);
+        assert_eq!(reconstructed.a, 2);
+        assert_eq!(reconstructed.b, 1);
+    }
+}
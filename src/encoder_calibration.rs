@@ -0,0 +1,392 @@
+//! Calibration of the electrical offset, direction and pole pairs of an
+//! absolute encoder.
+//!
+//! An [`EncoderCalibration`] first aligns the rotor by applying a current
+//! vector on the d-axis at an electrical angle of zero, and then slowly
+//! rotates the vector forwards and backwards through a number of electrical
+//! revolutions while recording the encoder counts at evenly spaced angles.
+//! The rotor lags the vector in the direction of travel due to friction, so
+//! the forward and backward readings at each angle are averaged to cancel it.
+//!
+//! The results can be used to configure an
+//! [`AngleAdapter`](crate::angle::AngleAdapter).
+
+use fixed::types::I16F16;
+
+use crate::{angle, pwm, Foc};
+
+/// Errors from checking the consistency of the calibration readings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CalibrationError {
+    /// The encoder did not move, or moved too little to determine the pole
+    /// pairs.
+    NoMovement,
+    /// The travel of the encoder was not close to a whole number of pole
+    /// pairs, e.g. because the rotor slipped or the counts per revolution are
+    /// wrong.
+    PolePairMismatch,
+    /// The forward and backward readings at the same angle differed by more
+    /// than a quarter of an electrical revolution, e.g. because the rotor
+    /// was stuck.
+    Hysteresis,
+    /// The offset calculated at each angle differed from the average by more
+    /// than the tolerance, e.g. because of a non-linear encoder. Contains the
+    /// largest difference in radians.
+    InconsistentOffset(I16F16),
+}
+
+/// The result of a successful calibration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncoderCalibrationResult {
+    /// The electrical offset in radians, for
+    /// [`AngleAdapter::with_electrical_offset`](crate::angle::AngleAdapter::with_electrical_offset).
+    pub electrical_offset: I16F16,
+    /// Whether the encoder counts down as the electrical angle increases,
+    /// for [`AngleAdapter::with_inverted`](crate::angle::AngleAdapter::with_inverted).
+    pub inverted: bool,
+    /// The number of pole pairs of the motor.
+    pub pole_pairs: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    Aligning,
+    Forward,
+    Backward,
+    Done,
+}
+
+/// Calibrates an absolute encoder, with `SAMPLES` readings in each
+/// direction.
+///
+/// The calibration is updated once per control tick with the raw encoder
+/// counts, and does not allocate.
+pub struct EncoderCalibration<const SAMPLES: usize> {
+    counts_per_revolution: u32,
+    current: I16F16,
+    electrical_revolutions: u8,
+    velocity: I16F16,
+    align_time: I16F16,
+    tolerance: I16F16,
+    phase: Phase,
+    time: I16F16,
+    angle: I16F16,
+    last_counts: Option<u32>,
+    position: i64,
+    next_sample: usize,
+    forward: [i64; SAMPLES],
+    backward: [i64; SAMPLES],
+    result: Option<Result<EncoderCalibrationResult, CalibrationError>>,
+}
+
+impl<const SAMPLES: usize> EncoderCalibration<SAMPLES> {
+    /// Create a new calibration.
+    ///
+    /// Params:
+    /// - `counts_per_revolution`: counts in one revolution of the encoder
+    /// - `current`: d-axis current in amps to hold the rotor with
+    /// - `electrical_revolutions`: number of electrical revolutions to sweep
+    ///   through in each direction
+    /// - `velocity`: electrical velocity of the sweep in rad/s
+    ///
+    /// By default, the rotor is aligned for 1 second before the sweep, and
+    /// the offset at each angle must be within 0.3 radians of the average.
+    pub fn new(
+        counts_per_revolution: u32,
+        current: I16F16,
+        electrical_revolutions: u8,
+        velocity: I16F16,
+    ) -> Self {
+        Self {
+            counts_per_revolution: counts_per_revolution.max(1),
+            current,
+            electrical_revolutions: electrical_revolutions.max(1),
+            velocity: velocity.saturating_abs(),
+            align_time: I16F16::ONE,
+            tolerance: I16F16::lit("0.3"),
+            phase: Phase::Aligning,
+            time: I16F16::ZERO,
+            angle: I16F16::ZERO,
+            last_counts: None,
+            position: 0,
+            next_sample: 0,
+            forward: [0; SAMPLES],
+            backward: [0; SAMPLES],
+            result: None,
+        }
+    }
+
+    /// Set the time in seconds to hold the rotor at an angle of zero before
+    /// the sweep.
+    pub fn with_align_time(mut self, align_time: I16F16) -> Self {
+        self.align_time = align_time;
+        self
+    }
+
+    /// Set the largest allowed difference in radians between the offset at
+    /// each angle and the average offset.
+    pub fn with_tolerance(mut self, tolerance: I16F16) -> Self {
+        self.tolerance = tolerance;
+        self
+    }
+
+    /// Restart the calibration from the alignment.
+    pub fn restart(&mut self) {
+        self.phase = Phase::Aligning;
+        self.time = I16F16::ZERO;
+        self.angle = I16F16::ZERO;
+        self.last_counts = None;
+        self.position = 0;
+        self.next_sample = 0;
+        self.result = None;
+    }
+
+    /// The electrical angle in radians of the d-axis current vector to apply,
+    /// in `[0, 2π)`.
+    pub fn angle(&self) -> I16F16 {
+        angle::wrap(self.angle)
+    }
+
+    /// Whether the calibration has finished.
+    pub fn is_done(&self) -> bool {
+        self.phase == Phase::Done
+    }
+
+    /// The result of the calibration, once it has finished.
+    pub fn result(&self) -> Option<&Result<EncoderCalibrationResult, CalibrationError>> {
+        self.result.as_ref()
+    }
+
+    /// The electrical angle of the sample with the given index, relative to
+    /// the start of the sweep.
+    fn sample_angle(&self, index: usize) -> I16F16 {
+        let span = I16F16::TAU.saturating_mul_int(i32::from(self.electrical_revolutions));
+        span / SAMPLES as i32 * index as i32
+    }
+
+    /// Update the calibration with the raw encoder counts.
+    ///
+    /// `dt` is the time since the last update in seconds. Returns the result
+    /// once the calibration has finished, after which the current vector
+    /// should no longer be applied.
+    pub fn update(
+        &mut self,
+        counts: u32,
+        dt: I16F16,
+    ) -> Option<&Result<EncoderCalibrationResult, CalibrationError>> {
+        // Track the position across wraps, assuming that the encoder moves
+        // less than half a revolution per update
+        let cpr = i64::from(self.counts_per_revolution);
+        let counts = counts % self.counts_per_revolution;
+        match self.last_counts {
+            Some(last) => {
+                let delta = (i64::from(counts) - i64::from(last)).rem_euclid(cpr);
+                self.position += if delta >= (cpr + 1) / 2 {
+                    delta - cpr
+                } else {
+                    delta
+                };
+            }
+            None => self.position = i64::from(counts),
+        }
+        self.last_counts = Some(counts);
+
+        let end = I16F16::TAU.saturating_mul_int(i32::from(self.electrical_revolutions));
+        let step = self.velocity.saturating_mul(dt);
+        match self.phase {
+            Phase::Aligning => {
+                self.time = self.time.saturating_add(dt);
+                if self.time >= self.align_time {
+                    self.phase = Phase::Forward;
+                }
+            }
+            Phase::Forward => {
+                while self.next_sample < SAMPLES
+                    && self.angle >= self.sample_angle(self.next_sample)
+                {
+                    self.forward[self.next_sample] = self.position;
+                    self.next_sample += 1;
+                }
+                if self.angle >= end {
+                    self.phase = Phase::Backward;
+                } else {
+                    self.angle = self.angle.saturating_add(step).min(end);
+                }
+            }
+            Phase::Backward => {
+                while self.next_sample > 0 && self.angle <= self.sample_angle(self.next_sample - 1)
+                {
+                    self.next_sample -= 1;
+                    self.backward[self.next_sample] = self.position;
+                }
+                if self.next_sample == 0 {
+                    self.phase = Phase::Done;
+                    self.result = Some(self.calculate());
+                } else {
+                    self.angle = self.angle.saturating_sub(step).max(I16F16::ZERO);
+                }
+            }
+            Phase::Done => {}
+        }
+
+        self.result.as_ref()
+    }
+
+    /// Drive the controller with the d-axis current vector.
+    ///
+    /// The current is applied as a q-axis current 90° behind the calibration
+    /// angle, so that the d-axis current controller is not needed.
+    ///
+    /// Params:
+    /// - `foc`: the controller to update
+    /// - `currents`: phase currents in amps
+    /// - `dt`: time delta since last update, in seconds
+    ///
+    /// Returns:
+    /// - The 3 PWM values to be set on your timer channels.
+    pub fn apply<Modulator: pwm::Modulation, const PWM_RESOLUTION: u16>(
+        &self,
+        foc: &mut Foc<Modulator, PWM_RESOLUTION>,
+        currents: [I16F16; 2],
+        dt: I16F16,
+    ) -> [u16; 3] {
+        let angle = angle::wrap(self.angle - I16F16::FRAC_PI_2);
+        foc.update(currents, angle, I16F16::ZERO, self.current, dt)
+    }
+
+    fn calculate(&self) -> Result<EncoderCalibrationResult, CalibrationError> {
+        if SAMPLES < 2 {
+            return Err(CalibrationError::NoMovement);
+        }
+        let cpr = i64::from(self.counts_per_revolution);
+
+        // The average of the forward and backward readings cancels the lag,
+        // doubled to keep the half counts
+        let midpoint = |index: usize| self.forward[index] + self.backward[index];
+        let travel = midpoint(SAMPLES - 1) - midpoint(0);
+        if travel == 0 {
+            return Err(CalibrationError::NoMovement);
+        }
+        let inverted = travel < 0;
+
+        // The electrical travel between the first and last sample, in
+        // electrical revolutions, doubled to match the travel
+        let electrical_travel =
+            2 * i64::from(self.electrical_revolutions) * cpr * (SAMPLES as i64 - 1)
+                / SAMPLES as i64;
+        let pole_pairs = (electrical_travel + travel.abs() / 2) / travel.abs();
+        if pole_pairs == 0 || pole_pairs > i64::from(u8::MAX) {
+            return Err(CalibrationError::NoMovement);
+        }
+        if (travel.abs() * pole_pairs - electrical_travel).abs() * 20 > electrical_travel {
+            return Err(CalibrationError::PolePairMismatch);
+        }
+
+        // Counts in a quarter of an electrical revolution
+        let quarter = cpr / (4 * pole_pairs);
+        if (0..SAMPLES).any(|index| (self.forward[index] - self.backward[index]).abs() > quarter) {
+            return Err(CalibrationError::Hysteresis);
+        }
+
+        // The offset at each sample is the electrical angle measured by the
+        // encoder, less the electrical angle of the current vector
+        let offset = |index: usize| {
+            let counts = (midpoint(index) / 2).rem_euclid(cpr);
+            let counts = if inverted {
+                (cpr - counts) % cpr
+            } else {
+                counts
+            };
+            let electrical_counts = (counts * pole_pairs) % cpr;
+            let measured = I16F16::from_bits(
+                (i128::from(electrical_counts) * i128::from(I16F16::TAU.to_bits())
+                    / i128::from(cpr)) as i32,
+            );
+            angle::difference(measured, self.sample_angle(index))
+        };
+
+        let (mut sin_sum, mut cos_sum) = (0i64, 0i64);
+        for index in 0..SAMPLES {
+            let (sin, cos) = cordic::sin_cos(offset(index));
+            sin_sum += i64::from(sin.to_bits());
+            cos_sum += i64::from(cos.to_bits());
+        }
+        let scale = sin_sum.unsigned_abs().max(cos_sum.unsigned_abs()).max(1);
+        let to_fixed =
+            |sum: i64| I16F16::from_bits((i128::from(sum) * 65536 / i128::from(scale)) as i32);
+        let electrical_offset = angle::wrap(angle::atan2(to_fixed(sin_sum), to_fixed(cos_sum)));
+
+        let max_error = (0..SAMPLES)
+            .map(|index| angle::difference(offset(index), electrical_offset).abs())
+            .max()
+            .unwrap_or(I16F16::ZERO);
+        if max_error > self.tolerance {
+            return Err(CalibrationError::InconsistentOffset(max_error));
+        }
+
+        Ok(EncoderCalibrationResult {
+            electrical_offset,
+            inverted,
+            pole_pairs: pole_pairs as u8,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CPR: u32 = 16384;
+
+    /// The counts of an encoder counting down, with the rotor lagging the
+    /// current vector by `lag` radians.
+    fn counts(electrical_angle: f32, lag: f32, pole_pairs: f32, mechanical_offset: f32) -> u32 {
+        let mechanical = (electrical_angle - lag) / pole_pairs + mechanical_offset;
+        let counts = (mechanical / core::f32::consts::TAU * CPR as f32).round() as i64;
+        (-counts).rem_euclid(i64::from(CPR)) as u32
+    }
+
+    #[test]
+    fn finds_offset_direction_and_pole_pairs() {
+        let mut calibration =
+            EncoderCalibration::<32>::new(CPR, I16F16::from_num(5), 2, I16F16::from_num(4))
+                .with_align_time(I16F16::lit("0.1"));
+        let dt = I16F16::from_num(1. / 1024.);
+
+        // Track the unwrapped angle of the current vector
+        let mut electrical_angle = 0f32;
+        let mut last_angle = I16F16::ZERO;
+        let result = loop {
+            let step = angle::difference(calibration.angle(), last_angle).to_num::<f32>();
+            electrical_angle += step;
+            last_angle = calibration.angle();
+
+            let lag = 0.2 * step.signum() * (step != 0.) as u8 as f32;
+            if let Some(result) = calibration.update(counts(electrical_angle, lag, 7., 1.), dt) {
+                break result.clone();
+            }
+        };
+
+        let result = result.unwrap();
+        assert!(result.inverted);
+        assert_eq!(result.pole_pairs, 7);
+        let expected = I16F16::from_num((7f32).rem_euclid(core::f32::consts::TAU));
+        let error = angle::difference(result.electrical_offset, expected);
+        assert!(error.abs() < 0.01, "offset {}", result.electrical_offset);
+    }
+
+    #[test]
+    fn stuck_rotor() {
+        let mut calibration =
+            EncoderCalibration::<16>::new(CPR, I16F16::from_num(5), 1, I16F16::from_num(20));
+        let dt = I16F16::from_num(1. / 1024.);
+
+        let result = loop {
+            if let Some(result) = calibration.update(1234, dt) {
+                break result.clone();
+            }
+        };
+        assert_eq!(result, Err(CalibrationError::NoMovement));
+        assert!(calibration.is_done());
+    }
+}
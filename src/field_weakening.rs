@@ -0,0 +1,152 @@
+//! Field weakening for operation above the base speed.
+//!
+//! Above the base speed the back-EMF of the motor approaches the bus voltage,
+//! and the current controllers run out of voltage to drive the torque
+//! current. Negative d-axis current opposes the flux of the magnets, which
+//! reduces the back-EMF and restores voltage headroom at the cost of extra
+//! current.
+//!
+//! [`FieldWeakening`] regulates the magnitude of the commanded voltage (see
+//! [`Foc::voltage_ratio`](crate::Foc::voltage_ratio)) to just below the
+//! maximum by integrating negative d-axis current. The output is used with
+//! [`Foc::set_d_current_setpoint`](crate::Foc::set_d_current_setpoint), which
+//! together with [`Foc::set_current_limit`](crate::Foc::set_current_limit)
+//! keeps the current vector within the current circle by giving the d-axis
+//! priority.
+
+use fixed::types::I16F16;
+
+use crate::pid::PIController;
+
+/// A voltage magnitude regulator which outputs a d-axis current setpoint.
+pub struct FieldWeakening {
+    controller: PIController,
+    threshold: I16F16,
+    d_current: I16F16,
+}
+
+impl FieldWeakening {
+    /// Create a new field weakening regulator.
+    ///
+    /// Params:
+    /// - `max_current`: the largest magnitude of d-axis current in amps, which
+    ///   should be no more than the current limit
+    /// - `gain`: integral gain in amps per second, per unit of voltage ratio
+    ///   above the threshold
+    ///
+    /// By default, the voltage ratio is regulated to 0.95.
+    pub fn new(max_current: I16F16, gain: I16F16) -> Self {
+        Self {
+            controller: PIController::new(I16F16::ZERO, -gain.saturating_abs())
+                .with_output_limits(-max_current.saturating_abs(), I16F16::ZERO),
+            threshold: I16F16::lit("0.95"),
+            d_current: I16F16::ZERO,
+        }
+    }
+
+    /// Set the voltage ratio above which the field is weakened, leaving the
+    /// remaining headroom for the current controllers to respond to
+    /// transients.
+    pub fn with_threshold(mut self, threshold: I16F16) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    /// Change the largest magnitude of d-axis current in amps, e.g. to follow
+    /// a changing current limit.
+    pub fn set_max_current(&mut self, max_current: I16F16) {
+        self.controller
+            .set_output_limits(-max_current.saturating_abs(), I16F16::ZERO);
+    }
+
+    /// Reset the d-axis current to zero.
+    pub fn reset(&mut self) {
+        self.controller.reset();
+        self.d_current = I16F16::ZERO;
+    }
+
+    /// The d-axis current setpoint from the last update, in amps.
+    pub fn d_current(&self) -> I16F16 {
+        self.d_current
+    }
+
+    /// Update the regulator with the voltage ratio from the last update of
+    /// the controller.
+    ///
+    /// `dt` is the time since the last update in seconds. Returns the d-axis
+    /// current setpoint in amps, which is zero or negative.
+    pub fn update(&mut self, voltage_ratio: I16F16, dt: I16F16) -> I16F16 {
+        self.d_current = self.controller.update(voltage_ratio, self.threshold, dt);
+        self.d_current
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{motor::MotorParameters, pwm, Foc};
+
+    #[test]
+    fn weakens_above_base_speed() {
+        let motor = MotorParameters::new(
+            I16F16::lit("0.1"),
+            I16F16::lit("0.2"),
+            I16F16::lit("0.2"),
+            I16F16::from_num(5),
+            7,
+        );
+        let dt = 1. / 16384.;
+        let mut foc: Foc<pwm::SpaceVector, 1000> = Foc::from_motor_parameters(
+            motor,
+            I16F16::from_num(2000),
+            I16F16::from_num(dt),
+            I16F16::from_num(12),
+        )
+        .with_current_limit(I16F16::from_num(20));
+        let mut field_weakening = FieldWeakening::new(I16F16::from_num(15), I16F16::from_num(2000));
+
+        // Accelerate until the back-EMF of 8 V is above the 6.9 V available
+        let mut angle = 0f32;
+        let mut current = [0f32; 2];
+        for step in 0..12288 {
+            let velocity = 1600. * (step as f32 / 4096.).min(1.);
+            let phase_currents = [
+                I16F16::from_num(current[0]),
+                I16F16::from_num(-current[0] / 2. + 3f32.sqrt() / 2. * current[1]),
+            ];
+            let d_current = field_weakening.update(foc.voltage_ratio(), I16F16::from_num(dt));
+            foc.set_d_current_setpoint(d_current);
+            foc.update(
+                phase_currents,
+                I16F16::from_num(angle),
+                I16F16::from_num(velocity),
+                I16F16::from_num(2),
+                I16F16::from_num(dt),
+            );
+
+            let voltage = foc.orthogonal_voltage();
+            let voltage = [voltage.alpha.to_num::<f32>(), voltage.beta.to_num()];
+            for _ in 0..10 {
+                let back_emf = [
+                    -velocity * 5e-3 * angle.sin(),
+                    velocity * 5e-3 * angle.cos(),
+                ];
+                for axis in 0..2 {
+                    let di = (voltage[axis] - back_emf[axis] - 0.1 * current[axis]) / 0.2e-3;
+                    current[axis] += di * dt / 10.;
+                }
+                angle = (angle + velocity * dt / 10.).rem_euclid(core::f32::consts::TAU);
+            }
+        }
+
+        // The torque current is reached with negative d-axis current
+        let rotating = foc.rotating_current();
+        assert!(
+            rotating.q.abs_diff(I16F16::from_num(2)) < 0.2,
+            "{rotating:?}"
+        );
+        assert!(rotating.d < -5, "{rotating:?}");
+        assert!(rotating.d.abs_diff(field_weakening.d_current()) < 0.5);
+        assert!(foc.voltage_ratio().abs_diff(I16F16::lit("0.95")) < 0.02);
+    }
+}
@@ -0,0 +1,319 @@
+//! Electrical angle estimation from three Hall sensors.
+//!
+//! Each of the six valid Hall states covers a 60° sector of the electrical
+//! revolution. A [`HallTable`] maps the states to the angle of the centre of
+//! their sector, and can either be configured from the order of the states or
+//! learned with a [`HallCalibration`].
+//!
+//! [`HallDecoder`] measures the time between transitions to interpolate the
+//! angle within a sector, and falls back to the centre of the sector (i.e.
+//! six-step commutation) at low speed. Its output can be used directly as the
+//! `angle` argument of [`Foc::update`](crate::Foc::update).
+
+use fixed::types::I16F16;
+
+use crate::angle;
+
+/// The angle of one sector, 60° in radians.
+const SECTOR_ANGLE: I16F16 = I16F16::lit("1.0471976");
+
+/// Errors from decoding the Hall state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HallError {
+    /// The state does not correspond to a sector, e.g. all sensors high or
+    /// all low, which indicates a disconnected sensor.
+    InvalidState(u8),
+}
+
+/// Mapping from the 3-bit Hall state to the electrical angle at the centre of
+/// its sector.
+#[derive(Debug, Clone)]
+pub struct HallTable {
+    angles: [Option<I16F16>; 8],
+}
+
+impl HallTable {
+    /// Create a table with the angle in radians at the centre of the sector
+    /// of each state, or `None` for invalid states.
+    pub fn new(angles: [Option<I16F16>; 8]) -> Self {
+        Self {
+            angles: angles.map(|angle| angle.map(angle::wrap)),
+        }
+    }
+
+    /// Create a table from the six valid states in order of increasing
+    /// electrical angle, where the first state is centred on `offset` radians.
+    pub fn from_sequence(sequence: [u8; 6], offset: I16F16) -> Self {
+        let mut angles = [None; 8];
+        for (i, state) in sequence.into_iter().enumerate() {
+            angles[usize::from(state & 0b111)] =
+                Some(angle::wrap(offset + SECTOR_ANGLE * i as i32));
+        }
+        Self { angles }
+    }
+
+    /// The angle in radians at the centre of the sector of the given state.
+    pub fn angle(&self, state: u8) -> Option<I16F16> {
+        self.angles[usize::from(state & 0b111)]
+    }
+}
+
+impl Default for HallTable {
+    /// The standard sequence for sensors spaced 120° apart, with state `0b001`
+    /// centred on zero.
+    fn default() -> Self {
+        Self::from_sequence([0b001, 0b011, 0b010, 0b110, 0b100, 0b101], I16F16::ZERO)
+    }
+}
+
+/// Learns a [`HallTable`] by recording the Hall state while the rotor is
+/// driven to known electrical angles, e.g. by slowly rotating an open-loop
+/// voltage vector.
+#[derive(Debug, Clone)]
+pub struct HallCalibration {
+    sin_sums: [i64; 8],
+    cos_sums: [i64; 8],
+    counts: [u32; 8],
+}
+
+impl HallCalibration {
+    /// Create a new empty calibration.
+    pub fn new() -> Self {
+        Self {
+            sin_sums: [0; 8],
+            cos_sums: [0; 8],
+            counts: [0; 8],
+        }
+    }
+
+    /// Record the Hall state at the given electrical angle in radians.
+    pub fn add_sample(&mut self, state: u8, electrical_angle: I16F16) {
+        let state = usize::from(state & 0b111);
+        let (sin, cos) = cordic::sin_cos(angle::wrap(electrical_angle));
+        self.sin_sums[state] += i64::from(sin.to_bits());
+        self.cos_sums[state] += i64::from(cos.to_bits());
+        self.counts[state] = self.counts[state].saturating_add(1);
+    }
+
+    /// The learned table, once exactly six states have been recorded.
+    ///
+    /// The angle of each state is the average of its recorded angles, so the
+    /// rotor should be moved through at least one full electrical revolution
+    /// at a constant speed.
+    pub fn table(&self) -> Option<HallTable> {
+        if self.counts.iter().filter(|&&count| count > 0).count() != 6 {
+            return None;
+        }
+
+        let mut angles = [None; 8];
+        for (state, angle) in angles.iter_mut().enumerate() {
+            if self.counts[state] == 0 {
+                continue;
+            }
+            // Only the direction of the sums matters, so scale them down to
+            // fit in an I16F16
+            let scale = self.sin_sums[state]
+                .unsigned_abs()
+                .max(self.cos_sums[state].unsigned_abs())
+                .max(1);
+            let to_fixed =
+                |sum: i64| I16F16::from_bits((i128::from(sum) * 65536 / i128::from(scale)) as i32);
+            *angle = Some(angle::wrap(angle::atan2(
+                to_fixed(self.sin_sums[state]),
+                to_fixed(self.cos_sums[state]),
+            )));
+        }
+        Some(HallTable { angles })
+    }
+}
+
+impl Default for HallCalibration {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Decodes Hall states into an interpolated electrical angle and velocity.
+pub struct HallDecoder {
+    table: HallTable,
+    min_velocity: I16F16,
+    last_state: Option<u8>,
+    sector_centre: I16F16,
+    transition_angle: Option<I16F16>,
+    direction: i32,
+    time_since_transition: I16F16,
+    velocity: I16F16,
+    angle: I16F16,
+}
+
+impl HallDecoder {
+    /// Create a new decoder with the given table.
+    ///
+    /// The angle is interpolated above `min_velocity` (electrical rad/s), and
+    /// is the centre of the sector below it.
+    pub fn new(table: HallTable, min_velocity: I16F16) -> Self {
+        Self {
+            table,
+            min_velocity,
+            last_state: None,
+            sector_centre: I16F16::ZERO,
+            transition_angle: None,
+            direction: 0,
+            time_since_transition: I16F16::ZERO,
+            velocity: I16F16::ZERO,
+            angle: I16F16::ZERO,
+        }
+    }
+
+    /// Change the table, e.g. after a [`HallCalibration`].
+    pub fn set_table(&mut self, table: HallTable) {
+        self.table = table;
+        self.last_state = None;
+        self.transition_angle = None;
+        self.velocity = I16F16::ZERO;
+    }
+
+    /// Update the decoder with the 3-bit Hall state.
+    ///
+    /// `dt` is the time since the last update in seconds. Returns the
+    /// electrical angle in radians, in `[0, 2π)`. If the state is invalid, the
+    /// error is returned and the last angle is kept.
+    pub fn update(&mut self, state: u8, dt: I16F16) -> Result<I16F16, HallError> {
+        let state = state & 0b111;
+        let Some(centre) = self.table.angle(state) else {
+            self.velocity = I16F16::ZERO;
+            self.transition_angle = None;
+            return Err(HallError::InvalidState(state));
+        };
+        self.time_since_transition = self.time_since_transition.saturating_add(dt);
+
+        if self.last_state != Some(state) {
+            let step = angle::difference(centre, self.sector_centre);
+            let direction = if step.is_negative() { -1 } else { 1 };
+            let adjacent = self.last_state.is_some() && step.abs() < SECTOR_ANGLE * 3 / 2;
+
+            if adjacent {
+                // The velocity is only known once a full sector has been
+                // crossed in the same direction
+                self.velocity = if self.transition_angle.is_some() && direction == self.direction {
+                    step.saturating_div(self.time_since_transition)
+                } else {
+                    I16F16::ZERO
+                };
+                self.transition_angle = Some(angle::wrap(self.sector_centre + step / 2));
+                self.direction = direction;
+            } else {
+                self.velocity = I16F16::ZERO;
+                self.transition_angle = None;
+            }
+
+            self.last_state = Some(state);
+            self.sector_centre = centre;
+            self.time_since_transition = I16F16::ZERO;
+        } else if self.time_since_transition > I16F16::ZERO {
+            // The velocity can be no higher than if the next transition
+            // happened now
+            let max_velocity = SECTOR_ANGLE.saturating_div(self.time_since_transition);
+            self.velocity = self.velocity.clamp(-max_velocity, max_velocity);
+        }
+
+        self.angle = match self.transition_angle {
+            Some(transition_angle) if self.velocity.abs() >= self.min_velocity => {
+                // Interpolate from the transition, but stay within the sector
+                let travelled = self
+                    .velocity
+                    .saturating_mul(self.time_since_transition)
+                    .clamp(-SECTOR_ANGLE, SECTOR_ANGLE);
+                angle::wrap(transition_angle + travelled)
+            }
+            _ => centre,
+        };
+        Ok(self.angle)
+    }
+
+    /// The electrical angle from the last update in radians, in `[0, 2π)`.
+    pub fn angle(&self) -> I16F16 {
+        self.angle
+    }
+
+    /// The electrical velocity in rad/s, measured from the time between
+    /// transitions.
+    pub fn velocity(&self) -> I16F16 {
+        self.velocity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The state of sensors spaced 120° apart at the given electrical angle,
+    /// matching the default table.
+    fn hall_state(angle: f32) -> u8 {
+        let sector = ((angle + core::f32::consts::FRAC_PI_6).rem_euclid(core::f32::consts::TAU)
+            / core::f32::consts::FRAC_PI_3) as usize;
+        [0b001, 0b011, 0b010, 0b110, 0b100, 0b101][sector.min(5)]
+    }
+
+    #[test]
+    fn interpolates_at_speed() {
+        let mut decoder = HallDecoder::new(HallTable::default(), I16F16::from_num(10));
+        let dt = 1. / 16384.;
+
+        for velocity in [200f32, -200f32] {
+            let mut angle = 1f32;
+            for step in 0..4096 {
+                let result = decoder
+                    .update(hall_state(angle), I16F16::from_num(dt))
+                    .unwrap();
+                if step > 2048 {
+                    let error = angle::difference(result, I16F16::from_num(angle));
+                    assert!(error.abs() < 0.05, "angle error {error}");
+                    assert!(decoder.velocity().abs_diff(I16F16::from_num(velocity)) < 5);
+                }
+                angle = (angle + velocity * dt).rem_euclid(core::f32::consts::TAU);
+            }
+        }
+    }
+
+    #[test]
+    fn six_step_at_standstill() {
+        let mut decoder = HallDecoder::new(HallTable::default(), I16F16::from_num(10));
+        let angle = decoder.update(0b011, I16F16::lit("0.001")).unwrap();
+        assert!(angle.abs_diff(SECTOR_ANGLE) < 0.001);
+
+        // A single transition does not give a velocity
+        let angle = decoder.update(0b010, I16F16::lit("0.001")).unwrap();
+        assert!(angle.abs_diff(SECTOR_ANGLE * 2) < 0.001);
+        assert_eq!(decoder.velocity(), 0);
+
+        assert_eq!(
+            decoder.update(0b111, I16F16::lit("0.001")),
+            Err(HallError::InvalidState(0b111))
+        );
+        assert_eq!(decoder.angle(), angle);
+    }
+
+    #[test]
+    fn learns_table() {
+        let offset = 0.4f32;
+        let mut calibration = HallCalibration::new();
+        for step in 0..720 {
+            let angle = step as f32 * core::f32::consts::TAU / 720.;
+            calibration.add_sample(hall_state(angle - offset), I16F16::from_num(angle));
+        }
+
+        let table = calibration.table().unwrap();
+        let expected = HallTable::from_sequence(
+            [0b001, 0b011, 0b010, 0b110, 0b100, 0b101],
+            I16F16::from_num(offset),
+        );
+        for state in 1..7 {
+            let error =
+                angle::difference(table.angle(state).unwrap(), expected.angle(state).unwrap());
+            assert!(error.abs() < 0.01, "state {state} error {error}");
+        }
+        assert!(table.angle(0).is_none());
+        assert!(table.angle(7).is_none());
+    }
+}
@@ -0,0 +1,465 @@
+//! High-frequency injection for sensorless position estimation at low speed
+//! and standstill.
+//!
+//! A square-wave voltage alternating every update is injected on the estimated
+//! d-axis with [`Foc::set_injection_voltage`]. On a salient motor (where the
+//! q-axis inductance is larger than the d-axis inductance) any error in the
+//! estimated angle causes a response in the estimated q-axis current, which is
+//! demodulated and tracked with a [`Pll`].
+//!
+//! The saliency repeats twice per electrical revolution, so the tracked angle
+//! may be off by π. This is resolved by applying a pair of voltage pulses on
+//! the d-axis: the current aligned with the magnet saturates the stator iron,
+//! lowering the inductance, so the current excursion is larger in the
+//! direction of the magnet's north pole.
+//!
+//! Each control period:
+//! 1. Call [`Foc::update`] with the angle from
+//!    [`HighFrequencyInjection::angle`].
+//! 2. Call [`HighFrequencyInjection::update`] with
+//!    [`Foc::orthogonal_current`].
+//! 3. Call [`Foc::set_injection_voltage`] with
+//!    [`HighFrequencyInjection::injection_voltage`].
+//!
+//! [`Foc::set_injection_voltage`]: crate::Foc::set_injection_voltage
+//! [`Foc::update`]: crate::Foc::update
+//! [`Foc::orthogonal_current`]: crate::Foc::orthogonal_current
+
+use fixed::types::I16F16;
+
+use crate::{
+    angle,
+    motor::MotorParameters,
+    park_clarke::{self, TwoPhaseReferenceFrame},
+    pll::Pll,
+    KILO,
+};
+
+/// Errors from creating a [`HighFrequencyInjection`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HfiError {
+    /// The q-axis inductance is not larger than the d-axis inductance, so the
+    /// response to the injected voltage does not depend on the angle.
+    NotSalient,
+}
+
+/// The current stage of the estimator.
+#[derive(Debug, Clone)]
+enum Stage {
+    /// Tracking the saliency, counting the number of updates.
+    Tracking { updates: u16 },
+    /// Applying the polarity detection pulses.
+    DetectingPolarity {
+        update: u32,
+        start: I16F16,
+        positive_excursion: I16F16,
+        negative_excursion: I16F16,
+    },
+}
+
+/// Position estimation by high-frequency square-wave injection.
+pub struct HighFrequencyInjection {
+    amplitude: I16F16,
+    inverse_inductance_difference: I16F16,
+    pulse_voltage: I16F16,
+    pulse_updates: u16,
+    settle_updates: u16,
+    pll: Pll,
+    stage: Stage,
+    polarity_detected: bool,
+    polarity_offset: I16F16,
+    positive: bool,
+    injection_voltage: I16F16,
+    last_current: Option<TwoPhaseReferenceFrame>,
+    last_difference: Option<TwoPhaseReferenceFrame>,
+}
+
+impl HighFrequencyInjection {
+    /// Create a new estimator for the given motor.
+    ///
+    /// Params:
+    /// - `amplitude`: amplitude of the injected voltage in volts
+    /// - `bandwidth`: bandwidth of the tracking PLL in rad/s, which must be
+    ///   well below the injection frequency (half the update frequency)
+    ///
+    /// Returns [`HfiError::NotSalient`] unless the motor has a larger q-axis
+    /// inductance than d-axis inductance.
+    ///
+    /// Polarity detection is disabled until configured with
+    /// [`HighFrequencyInjection::with_polarity_detection`].
+    pub fn new(
+        motor: &MotorParameters,
+        amplitude: I16F16,
+        bandwidth: I16F16,
+    ) -> Result<Self, HfiError> {
+        if motor.d_inductance <= I16F16::ZERO || motor.q_inductance <= motor.d_inductance {
+            return Err(HfiError::NotSalient);
+        }
+        let inverse_inductance_difference = KILO
+            .saturating_div(motor.d_inductance)
+            .saturating_sub(KILO.saturating_div(motor.q_inductance));
+        if inverse_inductance_difference <= I16F16::ZERO {
+            return Err(HfiError::NotSalient);
+        }
+
+        Ok(Self {
+            amplitude,
+            inverse_inductance_difference,
+            pulse_voltage: I16F16::ZERO,
+            pulse_updates: 0,
+            settle_updates: 0,
+            pll: Pll::new(bandwidth),
+            stage: Stage::Tracking { updates: 0 },
+            polarity_detected: false,
+            polarity_offset: I16F16::ZERO,
+            positive: true,
+            injection_voltage: amplitude,
+            last_current: None,
+            last_difference: None,
+        })
+    }
+
+    /// Enable polarity detection.
+    ///
+    /// After tracking the saliency for `settle_updates` updates, a pulse of
+    /// `pulse_voltage` volts is applied on the d-axis for `pulse_updates`
+    /// updates, followed by an opposite pulse to return the current to zero.
+    /// The same is then repeated in the negative direction. The pulses should
+    /// be large enough to saturate the stator iron, but short compared to the
+    /// response time of the current controllers.
+    pub fn with_polarity_detection(
+        mut self,
+        pulse_voltage: I16F16,
+        pulse_updates: u16,
+        settle_updates: u16,
+    ) -> Self {
+        self.pulse_voltage = pulse_voltage;
+        self.pulse_updates = pulse_updates.max(1);
+        self.settle_updates = settle_updates;
+        self
+    }
+
+    /// Restart the estimator, including polarity detection.
+    pub fn restart(&mut self) {
+        self.pll.reset();
+        self.stage = Stage::Tracking { updates: 0 };
+        self.polarity_detected = false;
+        self.polarity_offset = I16F16::ZERO;
+        self.restart_injection();
+    }
+
+    fn restart_injection(&mut self) {
+        self.positive = true;
+        self.injection_voltage = self.amplitude;
+        self.last_current = None;
+        self.last_difference = None;
+    }
+
+    /// Update the estimator with the stationary frame current measured at the
+    /// start of the period in which the last injection voltage is applied.
+    ///
+    /// Params:
+    /// - `current`: measured stationary frame current in amps
+    /// - `dt`: time delta since last update in seconds
+    pub fn update(&mut self, current: &TwoPhaseReferenceFrame, dt: I16F16) {
+        let (sin_angle, cos_angle) = cordic::sin_cos(self.angle());
+
+        match &mut self.stage {
+            Stage::Tracking { updates } => {
+                *updates = updates.saturating_add(1);
+                if self.pulse_updates > 0
+                    && !self.polarity_detected
+                    && *updates > self.settle_updates
+                {
+                    self.stage = Stage::DetectingPolarity {
+                        update: 0,
+                        start: I16F16::ZERO,
+                        positive_excursion: I16F16::ZERO,
+                        negative_excursion: I16F16::ZERO,
+                    };
+                    self.injection_voltage = self.pulse_voltage;
+                    return;
+                }
+            }
+            Stage::DetectingPolarity {
+                update,
+                start,
+                positive_excursion,
+                negative_excursion,
+            } => {
+                // The current measured now is the response to the pulses
+                // before the one being applied
+                let d = park_clarke::park(cos_angle, sin_angle, current.clone()).d;
+                *update += 1;
+                let pulse_updates = u32::from(self.pulse_updates);
+                match *update {
+                    n if n == 1 || n == 2 * pulse_updates + 1 => *start = d,
+                    n if n == pulse_updates + 1 => *positive_excursion = d.saturating_sub(*start),
+                    n if n == 3 * pulse_updates + 1 => {
+                        *negative_excursion = start.saturating_sub(d)
+                    }
+                    _ => {}
+                }
+
+                // Positive and negative pulses, each followed by a pulse in the
+                // opposite direction to return the current to zero
+                let next = *update + 1;
+                if next <= pulse_updates || next > 3 * pulse_updates {
+                    self.injection_voltage = self.pulse_voltage;
+                } else {
+                    self.injection_voltage = -self.pulse_voltage;
+                }
+
+                if *update >= 4 * pulse_updates {
+                    // The larger excursion is towards the magnet's north pole
+                    if *negative_excursion > *positive_excursion {
+                        self.polarity_offset = angle::wrap(self.polarity_offset + I16F16::PI);
+                    }
+                    self.polarity_detected = true;
+                    self.stage = Stage::Tracking { updates: 0 };
+                    self.restart_injection();
+                }
+                return;
+            }
+        }
+
+        // The last injection voltage is being applied now, so the change in
+        // current is the response to the one before it. The difference between
+        // consecutive changes removes the fundamental current.
+        let difference = self
+            .last_current
+            .as_ref()
+            .map(|last| TwoPhaseReferenceFrame {
+                alpha: current.alpha.saturating_sub(last.alpha),
+                beta: current.beta.saturating_sub(last.beta),
+            });
+        if let (Some(difference), Some(last_difference)) = (&difference, &self.last_difference) {
+            let response = park_clarke::park(
+                cos_angle,
+                sin_angle,
+                TwoPhaseReferenceFrame {
+                    alpha: difference.alpha.saturating_sub(last_difference.alpha),
+                    beta: difference.beta.saturating_sub(last_difference.beta),
+                },
+            );
+
+            // The response on the estimated q-axis is twice the response to
+            // the previous injected voltage V, which is
+            // V·dt·(1/Ld - 1/Lq)·sin(2Δθ)/2
+            let q = if self.positive {
+                -response.q
+            } else {
+                response.q
+            };
+            let scale = self
+                .amplitude
+                .saturating_mul(self.inverse_inductance_difference)
+                .saturating_mul(dt)
+                .saturating_mul_int(2);
+            if scale > I16F16::ZERO {
+                self.pll.update_phase_error(q.saturating_div(scale), dt);
+            }
+        }
+        self.last_current = Some(current.clone());
+        self.last_difference = difference;
+
+        self.positive = !self.positive;
+        self.injection_voltage = if self.positive {
+            self.amplitude
+        } else {
+            -self.amplitude
+        };
+    }
+
+    /// The d-axis voltage to inject during the next update, in volts.
+    pub fn injection_voltage(&self) -> I16F16 {
+        self.injection_voltage
+    }
+
+    /// The estimated electrical angle in radians, in `[0, 2π)`.
+    pub fn angle(&self) -> I16F16 {
+        angle::wrap(self.pll.angle() + self.polarity_offset)
+    }
+
+    /// The estimated electrical velocity in rad/s.
+    pub fn velocity(&self) -> I16F16 {
+        self.pll.velocity()
+    }
+
+    /// Whether the polarity of the magnet has been detected, so that
+    /// [`HighFrequencyInjection::angle`] is not off by π.
+    pub fn is_polarity_detected(&self) -> bool {
+        self.polarity_detected
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{pwm, Foc};
+
+    const RESISTANCE: f32 = 0.5;
+    const D_INDUCTANCE: f32 = 0.5e-3;
+    const Q_INDUCTANCE: f32 = 1e-3;
+    const DT: f32 = 1. / 16384.;
+
+    fn motor() -> MotorParameters {
+        MotorParameters::new(
+            I16F16::from_num(RESISTANCE),
+            I16F16::from_num(D_INDUCTANCE * 1e3),
+            I16F16::from_num(Q_INDUCTANCE * 1e3),
+            I16F16::from_num(10),
+            7,
+        )
+    }
+
+    fn estimator() -> HighFrequencyInjection {
+        HighFrequencyInjection::new(&motor(), I16F16::from_num(2), I16F16::from_num(100))
+            .unwrap()
+            .with_polarity_detection(I16F16::from_num(6), 8, 6000)
+    }
+
+    /// A salient motor held at an electrical angle, where positive d-axis
+    /// current saturates the d-axis inductance.
+    struct Plant {
+        sin_cos: (f32, f32),
+        current: [f32; 2],
+    }
+
+    impl Plant {
+        fn new(rotor_angle: f32) -> Self {
+            Self {
+                sin_cos: rotor_angle.sin_cos(),
+                current: [0.; 2],
+            }
+        }
+
+        /// The stationary frame current.
+        fn current(&self) -> TwoPhaseReferenceFrame {
+            let (sin, cos) = self.sin_cos;
+            let [d, q] = self.current;
+            TwoPhaseReferenceFrame {
+                alpha: I16F16::from_num(cos * d - sin * q),
+                beta: I16F16::from_num(sin * d + cos * q),
+            }
+        }
+
+        /// Apply a stationary frame voltage for one update.
+        fn apply(&mut self, alpha: f32, beta: f32) {
+            let (sin, cos) = self.sin_cos;
+            let voltage = [cos * alpha + sin * beta, cos * beta - sin * alpha];
+            let saturation = 1. - 0.3 * (self.current[0] / 5.).clamp(0., 1.);
+            let inductance = [D_INDUCTANCE * saturation, Q_INDUCTANCE];
+            for axis in 0..2 {
+                self.current[axis] +=
+                    DT / inductance[axis] * (voltage[axis] - RESISTANCE * self.current[axis]);
+            }
+        }
+    }
+
+    #[test]
+    fn detects_angle_and_polarity() {
+        for step in 0..8 {
+            let rotor_angle = step as f32 * core::f32::consts::TAU / 8. + 0.3;
+            let mut hfi = estimator();
+            let mut plant = Plant::new(rotor_angle);
+
+            for _ in 0..8000 {
+                let (sin, cos) = hfi.angle().to_num::<f32>().sin_cos();
+                let voltage = hfi.injection_voltage().to_num::<f32>();
+                hfi.update(&plant.current(), I16F16::from_num(DT));
+                plant.apply(voltage * cos, voltage * sin);
+            }
+
+            assert!(hfi.is_polarity_detected());
+            let error = angle::difference(hfi.angle(), I16F16::from_num(rotor_angle));
+            assert!(error.abs() < 0.05, "angle error {error} at {rotor_angle}");
+        }
+    }
+
+    /// Run the estimator through [`Foc`] with the given current loop
+    /// bandwidth, returning the estimator and the largest change in the d-axis
+    /// voltage from the current controllers between consecutive updates over
+    /// the last 1000 updates.
+    fn run_through_foc(
+        rotor_angle: f32,
+        bandwidth: u16,
+        hfi: HighFrequencyInjection,
+    ) -> (HighFrequencyInjection, f32) {
+        let dt = I16F16::from_num(DT);
+        let mut foc: Foc<pwm::SpaceVector, 1000> = Foc::from_motor_parameters(
+            motor(),
+            I16F16::from_num(bandwidth),
+            dt,
+            I16F16::from_num(24),
+        );
+        let mut hfi = hfi;
+        let mut plant = Plant::new(rotor_angle);
+        let mut last_voltage = 0f32;
+        let mut ripple = 0f32;
+
+        for update in 0..8000 {
+            let current = plant.current();
+            let currents = [
+                current.alpha,
+                (-current.alpha + crate::SQRT_3 * current.beta) / 2,
+            ];
+            let angle = hfi.angle();
+            let injection = foc.injection_voltage().to_num::<f32>();
+            foc.update(currents, angle, I16F16::ZERO, I16F16::ZERO, dt);
+            hfi.update(foc.orthogonal_current(), dt);
+            foc.set_injection_voltage(hfi.injection_voltage());
+
+            let voltage = foc.orthogonal_voltage();
+            let (alpha, beta) = (voltage.alpha.to_num::<f32>(), voltage.beta.to_num());
+            plant.apply(alpha, beta);
+
+            // The d-axis voltage from the current controllers alone
+            let (sin, cos) = angle.to_num::<f32>().sin_cos();
+            let controller_voltage = cos * alpha + sin * beta - injection;
+            if update >= 7000 {
+                ripple = ripple.max((controller_voltage - last_voltage).abs());
+            }
+            last_voltage = controller_voltage;
+        }
+
+        (hfi, ripple)
+    }
+
+    #[test]
+    fn estimates_through_foc() {
+        for step in 0..8 {
+            let rotor_angle = step as f32 * core::f32::consts::TAU / 8. + 1.;
+
+            // The current loop is slow compared to the polarity detection
+            // pulses, so that it does not mask the saturation
+            let (hfi, _) = run_through_foc(rotor_angle, 50, estimator());
+            assert!(hfi.is_polarity_detected());
+            let error = angle::difference(hfi.angle(), I16F16::from_num(rotor_angle));
+            assert!(error.abs() < 0.05, "angle error {error} at {rotor_angle}");
+
+            // The controllers act on the average of the last two currents, so
+            // a fast current loop does not respond to the injection
+            let hfi =
+                HighFrequencyInjection::new(&motor(), I16F16::from_num(2), I16F16::from_num(100))
+                    .unwrap();
+            let (hfi, ripple) = run_through_foc(rotor_angle, 2000, hfi);
+            let error = angle::difference(hfi.angle(), I16F16::from_num(rotor_angle));
+            let error = error.abs().min(I16F16::PI - error.abs());
+            assert!(error < 0.05, "angle error {error} at {rotor_angle}");
+            assert!(ripple < 0.02, "ripple {ripple} at {rotor_angle}");
+        }
+    }
+
+    #[test]
+    fn rejects_non_salient_motor() {
+        let mut motor = motor();
+        motor.q_inductance = motor.d_inductance;
+        let hfi = HighFrequencyInjection::new(&motor, I16F16::ONE, I16F16::ONE);
+        assert_eq!(hfi.err(), Some(HfiError::NotSalient));
+
+        motor.d_inductance = I16F16::ZERO;
+        let hfi = HighFrequencyInjection::new(&motor, I16F16::ONE, I16F16::ONE);
+        assert_eq!(hfi.err(), Some(HfiError::NotSalient));
+    }
+}
@@ -0,0 +1,907 @@
+//! Identification of the parameters of a motor.
+//!
+//! The routines here are updated once per control tick in place of
+//! [`Foc::update`], and drive the motor through [`Foc`] while measuring its
+//! response. They do not allocate, and report their result once finished.
+//!
+//! [`ElectricalIdentification`] measures the phase resistance and the d and
+//! q-axis inductances with the rotor held still. Its result can be used to
+//! create the current controllers, and to fill in
+//! [`MotorParameters`].
+//!
+//! [`FluxLinkageIdentification`] then measures the flux linkage from the
+//! back-EMF while the motor is spinning, and [`MechanicalIdentification`]
+//! measures the inertia and viscous friction from the response to a torque
+//! step.
+
+use fixed::types::{I16F16, I32F32};
+
+use crate::{
+    motor::MotorParameters,
+    park_clarke::{self, RotatingReferenceFrame},
+    pid::{self, PIController},
+    pwm, Foc, KILO,
+};
+
+/// Number of voltage steps used to measure the resistance.
+const RESISTANCE_STEPS: usize = 4;
+
+/// Errors from identifying the parameters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdentificationError {
+    /// The current did not change with the voltage, e.g. because the motor is
+    /// disconnected.
+    NoCurrent,
+    /// A measured parameter was not positive, or out of range.
+    OutOfRange,
+    /// The motor did not move, so its back-EMF or acceleration could not be
+    /// measured.
+    NoMovement,
+}
+
+/// The electrical parameters measured by [`ElectricalIdentification`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ElectricalParameters {
+    /// Phase resistance, in ohms.
+    pub resistance: I16F16,
+    /// d-axis inductance, in millihenries.
+    pub d_inductance: I16F16,
+    /// q-axis inductance, in millihenries.
+    pub q_inductance: I16F16,
+}
+
+impl ElectricalParameters {
+    /// Create the flux (d-axis) and torque (q-axis) current controllers for
+    /// [`Foc::new`], with the given bandwidth in rad/s and loop period in
+    /// seconds.
+    ///
+    /// These are the same controllers as [`Foc::from_motor_parameters`]
+    /// creates, see [`PIController::current_loop`].
+    pub fn current_controllers(
+        &self,
+        bandwidth: I16F16,
+        period: I16F16,
+    ) -> (PIController, PIController) {
+        pid::current_loops(
+            self.resistance,
+            self.d_inductance,
+            self.q_inductance,
+            bandwidth,
+            period,
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ElectricalPhase {
+    Resistance { step: usize },
+    DInductance,
+    QInductance,
+    Done,
+}
+
+/// Measures the phase resistance and inductances of a motor at standstill.
+///
+/// The resistance is measured by applying a series of increasing d-axis
+/// voltages at an electrical angle of zero, which also aligns the rotor, and
+/// fitting a line to the resulting currents. The slope of the line is the
+/// resistance, which removes the effect of a constant voltage drop across
+/// the switches.
+///
+/// The inductances are then measured by adding a square wave voltage which
+/// alternates every update, first on the d-axis and then on the q-axis, and
+/// measuring the change in current over each update. The square wave on the
+/// q-axis has no average, so it does not move the rotor.
+pub struct ElectricalIdentification {
+    voltage: I16F16,
+    injection_voltage: I16F16,
+    settle_time: I16F16,
+    measure_time: I16F16,
+    phase: ElectricalPhase,
+    time: I16F16,
+    output: RotatingReferenceFrame,
+    last_output: RotatingReferenceFrame,
+    last_current: RotatingReferenceFrame,
+    current_sum: i64,
+    count: i64,
+    step_currents: [I16F16; RESISTANCE_STEPS],
+    volt_seconds: I32F32,
+    current_change: i64,
+    d_inductance: I16F16,
+    result: Option<Result<ElectricalParameters, IdentificationError>>,
+}
+
+impl ElectricalIdentification {
+    /// Create a new identification.
+    ///
+    /// Params:
+    /// - `voltage`: the largest d-axis voltage in volts used to measure the
+    ///   resistance, which should give around the rated current
+    /// - `injection_voltage`: the amplitude of the square wave in volts used
+    ///   to measure the inductances
+    ///
+    /// By default, each step settles for 0.2 seconds and is then measured
+    /// for 0.2 seconds.
+    pub fn new(voltage: I16F16, injection_voltage: I16F16) -> Self {
+        let mut identification = Self {
+            voltage: voltage.saturating_abs(),
+            injection_voltage: injection_voltage.saturating_abs(),
+            settle_time: I16F16::lit("0.2"),
+            measure_time: I16F16::lit("0.2"),
+            phase: ElectricalPhase::Resistance { step: 0 },
+            time: I16F16::ZERO,
+            output: zero(),
+            last_output: zero(),
+            last_current: zero(),
+            current_sum: 0,
+            count: 0,
+            step_currents: [I16F16::ZERO; RESISTANCE_STEPS],
+            volt_seconds: I32F32::ZERO,
+            current_change: 0,
+            d_inductance: I16F16::ZERO,
+            result: None,
+        };
+        identification.restart();
+        identification
+    }
+
+    /// Set the time in seconds to wait for the current to settle at each
+    /// step, and the time in seconds to measure it for.
+    pub fn with_timing(mut self, settle_time: I16F16, measure_time: I16F16) -> Self {
+        self.settle_time = settle_time;
+        self.measure_time = measure_time;
+        self
+    }
+
+    /// Restart the identification from the first resistance step.
+    pub fn restart(&mut self) {
+        self.phase = ElectricalPhase::Resistance { step: 0 };
+        self.time = I16F16::ZERO;
+        self.output = RotatingReferenceFrame {
+            d: self.step_voltage(0),
+            q: I16F16::ZERO,
+        };
+        self.last_output = zero();
+        self.last_current = zero();
+        self.current_sum = 0;
+        self.count = 0;
+        self.volt_seconds = I32F32::ZERO;
+        self.current_change = 0;
+        self.result = None;
+    }
+
+    /// Whether the identification has finished.
+    pub fn is_done(&self) -> bool {
+        self.phase == ElectricalPhase::Done
+    }
+
+    /// The result of the identification, once it has finished.
+    pub fn result(&self) -> Option<&Result<ElectricalParameters, IdentificationError>> {
+        self.result.as_ref()
+    }
+
+    /// The d-axis voltage of the given resistance step.
+    fn step_voltage(&self, step: usize) -> I16F16 {
+        self.voltage / RESISTANCE_STEPS as i32 * (step as i32 + 1)
+    }
+
+    /// Update the identification, applying the next voltage with the
+    /// controller.
+    ///
+    /// Params:
+    /// - `foc`: the controller to update
+    /// - `currents`: phase currents in amps
+    /// - `dt`: time delta since last update, in seconds
+    ///
+    /// Returns:
+    /// - The 3 PWM values to be set on your timer channels. Once the
+    ///   identification has finished these are for a zero voltage vector.
+    pub fn update<Modulator: pwm::Modulation, const PWM_RESOLUTION: u16>(
+        &mut self,
+        foc: &mut Foc<Modulator, PWM_RESOLUTION>,
+        currents: [I16F16; 2],
+        dt: I16F16,
+    ) -> [u16; 3] {
+        // The current measured now is the response to the voltage applied
+        // at the last update
+        let output = self.output.clone();
+        let pwm = foc.update_voltage(currents, I16F16::ZERO, I16F16::ZERO, output.clone());
+        let current = foc.rotating_current().clone();
+        let applied = core::mem::replace(&mut self.last_output, output);
+        let change = RotatingReferenceFrame {
+            d: current.d.saturating_sub(self.last_current.d),
+            q: current.q.saturating_sub(self.last_current.q),
+        };
+        self.last_current = current.clone();
+
+        self.time = self.time.saturating_add(dt);
+        let measuring = self.time > self.settle_time;
+        let finished = self.time >= self.settle_time.saturating_add(self.measure_time);
+
+        match self.phase {
+            ElectricalPhase::Resistance { step } => {
+                if measuring {
+                    self.current_sum += i64::from(current.d.to_bits());
+                    self.count += 1;
+                }
+                if finished {
+                    self.step_currents[step] =
+                        I16F16::from_bits((self.current_sum / self.count.max(1)) as i32);
+                    self.current_sum = 0;
+                    self.count = 0;
+                    self.time = I16F16::ZERO;
+                    self.phase = if step + 1 < RESISTANCE_STEPS {
+                        ElectricalPhase::Resistance { step: step + 1 }
+                    } else {
+                        ElectricalPhase::DInductance
+                    };
+                }
+            }
+            ElectricalPhase::DInductance | ElectricalPhase::QInductance => {
+                let (injected, change) = if self.phase == ElectricalPhase::DInductance {
+                    (applied.d.saturating_sub(self.voltage), change.d)
+                } else {
+                    (applied.q, change.q)
+                };
+                if measuring {
+                    // Correlate the change in current with the sign of the
+                    // injected voltage
+                    self.volt_seconds += I32F32::from(self.injection_voltage) * I32F32::from(dt);
+                    self.current_change += if injected.is_negative() {
+                        -i64::from(change.to_bits())
+                    } else {
+                        i64::from(change.to_bits())
+                    };
+                }
+                if finished {
+                    let inductance = self.inductance();
+                    self.volt_seconds = I32F32::ZERO;
+                    self.current_change = 0;
+                    self.time = I16F16::ZERO;
+                    if self.phase == ElectricalPhase::DInductance {
+                        self.d_inductance = inductance;
+                        self.phase = ElectricalPhase::QInductance;
+                    } else {
+                        self.phase = ElectricalPhase::Done;
+                        self.result = Some(self.calculate(inductance));
+                    }
+                }
+            }
+            ElectricalPhase::Done => {}
+        }
+
+        // The voltage for the next update
+        self.output = match self.phase {
+            ElectricalPhase::Resistance { step } => RotatingReferenceFrame {
+                d: self.step_voltage(step),
+                q: I16F16::ZERO,
+            },
+            ElectricalPhase::DInductance => RotatingReferenceFrame {
+                d: self
+                    .voltage
+                    .saturating_add(self.alternate(self.last_output.d, self.voltage)),
+                q: I16F16::ZERO,
+            },
+            ElectricalPhase::QInductance => RotatingReferenceFrame {
+                d: self.voltage,
+                q: self.alternate(self.last_output.q, I16F16::ZERO),
+            },
+            ElectricalPhase::Done => zero(),
+        };
+
+        pwm
+    }
+
+    /// The injected voltage for the next update, with the opposite sign to
+    /// the last injected voltage.
+    fn alternate(&self, last: I16F16, bias: I16F16) -> I16F16 {
+        if last.saturating_sub(bias).is_positive() {
+            -self.injection_voltage
+        } else {
+            self.injection_voltage
+        }
+    }
+
+    /// The inductance in millihenries from the measured volt-seconds and
+    /// change in current.
+    fn inductance(&self) -> I16F16 {
+        if self.current_change <= 0 {
+            return I16F16::ZERO;
+        }
+        // The volt-seconds have 32 fractional bits and the current 16, so the
+        // result has 16 fractional bits
+        let bits = i128::from(self.volt_seconds.to_bits()) * 1000 / i128::from(self.current_change);
+        I16F16::from_bits(bits.clamp(0, i32::MAX.into()) as i32)
+    }
+
+    fn calculate(&self, q_inductance: I16F16) -> Result<ElectricalParameters, IdentificationError> {
+        // Least squares fit of the voltage against the current
+        let n = RESISTANCE_STEPS as i128;
+        let (mut sum_i, mut sum_v, mut sum_ii, mut sum_iv) = (0i128, 0i128, 0i128, 0i128);
+        for (step, current) in self.step_currents.iter().enumerate() {
+            let i = i128::from(current.to_bits());
+            let v = i128::from(self.step_voltage(step).to_bits());
+            sum_i += i;
+            sum_v += v;
+            sum_ii += i * i;
+            sum_iv += i * v;
+        }
+        let denominator = n * sum_ii - sum_i * sum_i;
+        if denominator <= 0 {
+            return Err(IdentificationError::NoCurrent);
+        }
+        let resistance = (n * sum_iv - sum_i * sum_v) * 65536 / denominator;
+        let resistance = I16F16::from_bits(
+            i32::try_from(resistance).map_err(|_| IdentificationError::OutOfRange)?,
+        );
+
+        let parameters = ElectricalParameters {
+            resistance,
+            d_inductance: self.d_inductance,
+            q_inductance,
+        };
+        if parameters.resistance <= I16F16::ZERO
+            || parameters.d_inductance <= I16F16::ZERO
+            || parameters.q_inductance <= I16F16::ZERO
+        {
+            return Err(IdentificationError::OutOfRange);
+        }
+        Ok(parameters)
+    }
+}
+
+/// Measures the permanent magnet flux linkage from the back-EMF.
+///
+/// The motor must be spinning at a steady velocity, either closed-loop or
+/// open-loop (e.g. with an [`OpenLoopStartup`](crate::startup::OpenLoopStartup)).
+/// The back-EMF is the commanded voltage less the voltage across the
+/// resistance and inductance, and its magnitude is the flux linkage times the
+/// electrical velocity. Only magnitudes are used, so the angle does not need
+/// to be aligned with the rotor.
+///
+/// The inductance is taken as the average of the d and q-axis inductances,
+/// so the current should be small for a salient motor.
+pub struct FluxLinkageIdentification {
+    resistance: I16F16,
+    inductance: I16F16,
+    settle_time: I16F16,
+    measure_time: I16F16,
+    time: I16F16,
+    back_emf_sum: i64,
+    velocity_sum: i64,
+    result: Option<Result<I16F16, IdentificationError>>,
+}
+
+impl FluxLinkageIdentification {
+    /// Create a new identification with the measured electrical parameters.
+    ///
+    /// The back-EMF is measured for `measure_time` seconds, after waiting
+    /// `settle_time` seconds for the velocity and current to settle.
+    pub fn new(
+        parameters: &ElectricalParameters,
+        settle_time: I16F16,
+        measure_time: I16F16,
+    ) -> Self {
+        Self {
+            resistance: parameters.resistance,
+            inductance: parameters.d_inductance / 2 + parameters.q_inductance / 2,
+            settle_time,
+            measure_time,
+            time: I16F16::ZERO,
+            back_emf_sum: 0,
+            velocity_sum: 0,
+            result: None,
+        }
+    }
+
+    /// Restart the identification.
+    pub fn restart(&mut self) {
+        self.time = I16F16::ZERO;
+        self.back_emf_sum = 0;
+        self.velocity_sum = 0;
+        self.result = None;
+    }
+
+    /// The result of the identification in milliwebers, once it has
+    /// finished.
+    pub fn result(&self) -> Option<&Result<I16F16, IdentificationError>> {
+        self.result.as_ref()
+    }
+
+    /// Update the identification after updating the controller.
+    ///
+    /// `velocity` is the electrical velocity in rad/s, and `dt` is the time
+    /// since the last update in seconds. Returns the flux linkage in
+    /// milliwebers once the identification has finished.
+    pub fn update<Modulator: pwm::Modulation, const PWM_RESOLUTION: u16>(
+        &mut self,
+        foc: &Foc<Modulator, PWM_RESOLUTION>,
+        velocity: I16F16,
+        dt: I16F16,
+    ) -> Option<&Result<I16F16, IdentificationError>> {
+        if self.result.is_some() {
+            return self.result.as_ref();
+        }
+        self.time = self.time.saturating_add(dt);
+        if self.time <= self.settle_time {
+            return None;
+        }
+
+        // The voltage across the inductance in the steady state is jωL·i
+        let voltage = foc.orthogonal_voltage();
+        let current = foc.orthogonal_current();
+        let reactance = (velocity / KILO).saturating_mul(self.inductance);
+        let back_emf = park_clarke::TwoPhaseReferenceFrame {
+            alpha: voltage
+                .alpha
+                .saturating_sub(self.resistance.saturating_mul(current.alpha))
+                .saturating_add(reactance.saturating_mul(current.beta)),
+            beta: voltage
+                .beta
+                .saturating_sub(self.resistance.saturating_mul(current.beta))
+                .saturating_sub(reactance.saturating_mul(current.alpha)),
+        };
+        let magnitude = crate::hypot(back_emf.alpha, back_emf.beta);
+        self.back_emf_sum += i64::from(magnitude.to_bits());
+        self.velocity_sum += i64::from(velocity.saturating_abs().to_bits());
+
+        if self.time >= self.settle_time.saturating_add(self.measure_time) {
+            self.result = Some(if self.velocity_sum == 0 {
+                Err(IdentificationError::NoMovement)
+            } else {
+                let bits =
+                    i128::from(self.back_emf_sum) * 1000 * 65536 / i128::from(self.velocity_sum);
+                i32::try_from(bits)
+                    .map(I16F16::from_bits)
+                    .map_err(|_| IdentificationError::OutOfRange)
+            });
+        }
+        self.result.as_ref()
+    }
+}
+
+/// The mechanical parameters measured by [`MechanicalIdentification`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MechanicalParameters {
+    /// Rotor inertia, in g·m².
+    pub inertia: I16F16,
+    /// Viscous friction, in mN·m·s/rad.
+    pub viscous_friction: I16F16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MechanicalPhase {
+    Accelerating,
+    Coasting,
+    Done,
+}
+
+/// Measures the rotor inertia and viscous friction from a torque step.
+///
+/// A constant q-axis current is applied for a time, and then the current is
+/// set to zero while the motor coasts. Over each phase the change in
+/// velocity, the integral of the velocity and the integral of the torque are
+/// measured, which gives two equations for the inertia and friction
+/// (`J·Δω + B·∫ω dt = ∫T dt`).
+///
+/// Coulomb friction is not modelled, and increases the measured viscous
+/// friction. The measured friction is not allowed to be negative.
+pub struct MechanicalIdentification {
+    torque_constant: I16F16,
+    pole_pairs: u8,
+    current: I16F16,
+    acceleration_time: I16F16,
+    coast_time: I16F16,
+    phase: MechanicalPhase,
+    time: I16F16,
+    start_velocity: Option<I16F16>,
+    velocity_changes: [I32F32; 2],
+    velocity_integrals: [I32F32; 2],
+    torque_integrals: [I32F32; 2],
+    result: Option<Result<MechanicalParameters, IdentificationError>>,
+}
+
+impl MechanicalIdentification {
+    /// Create a new identification.
+    ///
+    /// Params:
+    /// - `motor`: the motor parameters, including the flux linkage and pole
+    ///   pairs
+    /// - `current`: q-axis current in amps to accelerate with
+    /// - `acceleration_time`: time in seconds to accelerate for, which must
+    ///   be short enough to stay within the safe velocity of the motor
+    /// - `coast_time`: time in seconds to coast for
+    pub fn new(
+        motor: &MotorParameters,
+        current: I16F16,
+        acceleration_time: I16F16,
+        coast_time: I16F16,
+    ) -> Self {
+        Self {
+            torque_constant: motor.torque_constant(),
+            pole_pairs: motor.pole_pairs.max(1),
+            current,
+            acceleration_time,
+            coast_time,
+            phase: MechanicalPhase::Accelerating,
+            time: I16F16::ZERO,
+            start_velocity: None,
+            velocity_changes: [I32F32::ZERO; 2],
+            velocity_integrals: [I32F32::ZERO; 2],
+            torque_integrals: [I32F32::ZERO; 2],
+            result: None,
+        }
+    }
+
+    /// Restart the identification.
+    pub fn restart(&mut self) {
+        self.phase = MechanicalPhase::Accelerating;
+        self.time = I16F16::ZERO;
+        self.start_velocity = None;
+        self.velocity_changes = [I32F32::ZERO; 2];
+        self.velocity_integrals = [I32F32::ZERO; 2];
+        self.torque_integrals = [I32F32::ZERO; 2];
+        self.result = None;
+    }
+
+    /// Whether the identification has finished.
+    pub fn is_done(&self) -> bool {
+        self.phase == MechanicalPhase::Done
+    }
+
+    /// The q-axis current in amps being commanded.
+    pub fn current(&self) -> I16F16 {
+        match self.phase {
+            MechanicalPhase::Accelerating => self.current,
+            MechanicalPhase::Coasting | MechanicalPhase::Done => I16F16::ZERO,
+        }
+    }
+
+    /// The result of the identification, once it has finished.
+    pub fn result(&self) -> Option<&Result<MechanicalParameters, IdentificationError>> {
+        self.result.as_ref()
+    }
+
+    /// Update the identification, commanding the current with the
+    /// controller.
+    ///
+    /// Params:
+    /// - `foc`: the controller to update
+    /// - `currents`: phase currents in amps
+    /// - `angle`: electrical angle in radians
+    /// - `velocity`: electrical velocity in rad/s
+    /// - `dt`: time delta since last update, in seconds
+    ///
+    /// Returns:
+    /// - The 3 PWM values to be set on your timer channels.
+    pub fn update<Modulator: pwm::Modulation, const PWM_RESOLUTION: u16>(
+        &mut self,
+        foc: &mut Foc<Modulator, PWM_RESOLUTION>,
+        currents: [I16F16; 2],
+        angle: I16F16,
+        velocity: I16F16,
+        dt: I16F16,
+    ) -> [u16; 3] {
+        let pwm = foc.update(currents, angle, velocity, self.current(), dt);
+        if self.phase == MechanicalPhase::Done {
+            return pwm;
+        }
+
+        let mechanical_velocity = velocity / i32::from(self.pole_pairs);
+        let start_velocity = *self.start_velocity.get_or_insert(mechanical_velocity);
+        let index = match self.phase {
+            MechanicalPhase::Accelerating => 0,
+            _ => 1,
+        };
+        let dt_wide = I32F32::from(dt);
+        let integrals = self.velocity_integrals[index]
+            .checked_add(I32F32::from(mechanical_velocity) * dt_wide)
+            .zip(
+                self.torque_integrals[index]
+                    .checked_add(I32F32::from(foc.rotating_current().q) * dt_wide),
+            );
+        let Some((velocity_integral, torque_integral)) = integrals else {
+            self.phase = MechanicalPhase::Done;
+            self.result = Some(Err(IdentificationError::OutOfRange));
+            return pwm;
+        };
+        self.velocity_integrals[index] = velocity_integral;
+        self.torque_integrals[index] = torque_integral;
+        self.velocity_changes[index] =
+            I32F32::from(mechanical_velocity) - I32F32::from(start_velocity);
+
+        self.time = self.time.saturating_add(dt);
+        match self.phase {
+            MechanicalPhase::Accelerating if self.time >= self.acceleration_time => {
+                self.phase = MechanicalPhase::Coasting;
+                self.time = I16F16::ZERO;
+                self.start_velocity = Some(mechanical_velocity);
+            }
+            MechanicalPhase::Coasting if self.time >= self.coast_time => {
+                self.phase = MechanicalPhase::Done;
+                self.result = Some(self.calculate());
+            }
+            _ => {}
+        }
+        pwm
+    }
+
+    fn calculate(&self) -> Result<MechanicalParameters, IdentificationError> {
+        let [change_1, change_2] = self.velocity_changes;
+        let [integral_1, integral_2] = self.velocity_integrals;
+        let torque = |integral: I32F32| {
+            integral
+                .checked_mul(I32F32::from(self.torque_constant))
+                .ok_or(IdentificationError::OutOfRange)
+        };
+        let [torque_1, torque_2] = [
+            torque(self.torque_integrals[0])?,
+            torque(self.torque_integrals[1])?,
+        ];
+
+        // Solve J·Δω + B·∫ω dt = ∫T dt for both phases. The sums grow with
+        // the length and speed of the capture, so the products can overflow.
+        let cross = |a: I32F32, b: I32F32, c: I32F32, d: I32F32| {
+            a.checked_mul(b)?.checked_sub(c.checked_mul(d)?)
+        };
+        let determinant = cross(change_1, integral_2, integral_1, change_2)
+            .ok_or(IdentificationError::OutOfRange)?;
+        if determinant == I32F32::ZERO {
+            return Err(IdentificationError::NoMovement);
+        }
+        let solve = |numerator: Option<I32F32>| {
+            numerator
+                .and_then(|numerator| numerator.checked_div(determinant))
+                .ok_or(IdentificationError::OutOfRange)
+        };
+        let inertia = solve(cross(torque_1, integral_2, integral_1, torque_2))?;
+        let friction = solve(cross(change_1, torque_2, torque_1, change_2))?;
+
+        let to_milli = |value: I32F32| -> Result<I16F16, IdentificationError> {
+            value
+                .checked_mul_int(1000)
+                .and_then(|value| value.checked_to_num())
+                .ok_or(IdentificationError::OutOfRange)
+        };
+        let parameters = MechanicalParameters {
+            inertia: to_milli(inertia)?,
+            viscous_friction: to_milli(friction)?.max(I16F16::ZERO),
+        };
+        if parameters.inertia <= I16F16::ZERO {
+            return Err(IdentificationError::OutOfRange);
+        }
+        Ok(parameters)
+    }
+}
+
+fn zero() -> RotatingReferenceFrame {
+    RotatingReferenceFrame {
+        d: I16F16::ZERO,
+        q: I16F16::ZERO,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::park_clarke;
+
+    /// A motor at standstill with the rotor at an electrical angle of zero,
+    /// and a voltage drop across the switches.
+    struct Motor {
+        resistance: f32,
+        d_inductance: f32,
+        q_inductance: f32,
+        current: [f32; 2],
+    }
+
+    impl Motor {
+        fn step(&mut self, voltage: &park_clarke::TwoPhaseReferenceFrame, dt: f32) {
+            let voltage = [voltage.alpha.to_num::<f32>(), voltage.beta.to_num()];
+            let inductance = [self.d_inductance, self.q_inductance];
+            for _ in 0..10 {
+                for axis in 0..2 {
+                    let drop = 0.1 * self.current[axis].signum();
+                    let di = (voltage[axis] - drop - self.resistance * self.current[axis])
+                        / inductance[axis];
+                    self.current[axis] += di * dt / 10.;
+                }
+            }
+        }
+
+        fn phase_currents(&self) -> [I16F16; 2] {
+            let [alpha, beta] = self.current;
+            [
+                I16F16::from_num(alpha),
+                I16F16::from_num(-alpha / 2. + 3f32.sqrt() / 2. * beta),
+            ]
+        }
+    }
+
+    #[test]
+    fn measures_resistance_and_inductance() {
+        let controller = || PIController::new(I16F16::ZERO, I16F16::ZERO);
+        let mut foc: Foc<pwm::SpaceVector, 1000> =
+            Foc::new(controller(), controller(), I16F16::from_num(24));
+        let mut motor = Motor {
+            resistance: 0.5,
+            d_inductance: 0.2e-3,
+            q_inductance: 0.3e-3,
+            current: [0.; 2],
+        };
+        let mut identification = ElectricalIdentification::new(I16F16::from_num(2), I16F16::ONE)
+            .with_timing(I16F16::lit("0.02"), I16F16::lit("0.05"));
+        let dt = 1. / 16384.;
+
+        while !identification.is_done() {
+            identification.update(&mut foc, motor.phase_currents(), I16F16::from_num(dt));
+            motor.step(foc.orthogonal_voltage(), dt);
+        }
+
+        let parameters = identification.result().unwrap().clone().unwrap();
+        assert!(
+            parameters.resistance.abs_diff(I16F16::lit("0.5")) < 0.01,
+            "{parameters:?}"
+        );
+        assert!(
+            parameters.d_inductance.abs_diff(I16F16::lit("0.2")) < 0.01,
+            "{parameters:?}"
+        );
+        assert!(
+            parameters.q_inductance.abs_diff(I16F16::lit("0.3")) < 0.015,
+            "{parameters:?}"
+        );
+
+        // The current reaches 63% of a step after one time constant
+        let (flux, torque) =
+            parameters.current_controllers(I16F16::from_num(2000), I16F16::from_num(dt));
+        let mut foc: Foc<pwm::SpaceVector, 1000> = Foc::new(flux, torque, I16F16::from_num(24));
+        motor.current = [0.; 2];
+        for _ in 0..8 {
+            foc.update(
+                motor.phase_currents(),
+                I16F16::ZERO,
+                I16F16::ZERO,
+                I16F16::from_num(2),
+                I16F16::from_num(dt),
+            );
+            motor.step(foc.orthogonal_voltage(), dt);
+        }
+        assert!((motor.current[1] - 1.26).abs() < 0.2, "{:?}", motor.current);
+    }
+
+    #[test]
+    fn measures_flux_linkage() {
+        let parameters = ElectricalParameters {
+            resistance: I16F16::lit("0.5"),
+            d_inductance: I16F16::lit("0.2"),
+            q_inductance: I16F16::lit("0.2"),
+        };
+        let dt = 1. / 16384.;
+        let (flux, torque) =
+            parameters.current_controllers(I16F16::from_num(2000), I16F16::from_num(dt));
+        let mut foc: Foc<pwm::SpaceVector, 1000> = Foc::new(flux, torque, I16F16::from_num(24));
+        let mut identification =
+            FluxLinkageIdentification::new(&parameters, I16F16::lit("0.05"), I16F16::lit("0.1"));
+
+        // Spin at a constant velocity with a current of 2 A
+        let (velocity, flux_linkage) = (1000f32, 5e-3f32);
+        let mut angle = 0f32;
+        let mut current = [0f32; 2];
+        let result = loop {
+            let phase_currents = [
+                I16F16::from_num(current[0]),
+                I16F16::from_num(-current[0] / 2. + 3f32.sqrt() / 2. * current[1]),
+            ];
+            foc.update(
+                phase_currents,
+                I16F16::from_num(angle),
+                I16F16::from_num(velocity),
+                I16F16::from_num(2),
+                I16F16::from_num(dt),
+            );
+            if let Some(result) =
+                identification.update(&foc, I16F16::from_num(velocity), I16F16::from_num(dt))
+            {
+                break *result;
+            }
+
+            let voltage = foc.orthogonal_voltage();
+            let voltage = [voltage.alpha.to_num::<f32>(), voltage.beta.to_num()];
+            for _ in 0..10 {
+                let back_emf = [
+                    -velocity * flux_linkage * angle.sin(),
+                    velocity * flux_linkage * angle.cos(),
+                ];
+                for axis in 0..2 {
+                    let di = (voltage[axis] - back_emf[axis] - 0.5 * current[axis]) / 0.2e-3;
+                    current[axis] += di * dt / 10.;
+                }
+                angle = (angle + velocity * dt / 10.).rem_euclid(core::f32::consts::TAU);
+            }
+        };
+
+        let result = result.unwrap();
+        assert!(result.abs_diff(I16F16::from_num(5)) < 0.15, "{result}");
+    }
+
+    #[test]
+    fn measures_inertia_and_friction() {
+        let motor = MotorParameters::new(
+            I16F16::lit("0.5"),
+            I16F16::lit("0.2"),
+            I16F16::lit("0.2"),
+            I16F16::from_num(5),
+            7,
+        );
+        let controller = || PIController::new(I16F16::ZERO, I16F16::ZERO);
+        let mut foc: Foc<pwm::SpaceVector, 1000> =
+            Foc::new(controller(), controller(), I16F16::from_num(24));
+        let mut identification = MechanicalIdentification::new(
+            &motor,
+            I16F16::from_num(2),
+            I16F16::lit("0.1"),
+            I16F16::lit("0.5"),
+        );
+
+        // 0.02 g·m² and 0.01 mN·m·s/rad, with the current following the
+        // setpoint exactly
+        let (inertia, friction) = (2e-5f32, 1e-5f32);
+        let torque_constant = 1.5 * 7. * 5e-3;
+        let dt = 1. / 16384.;
+        let (mut angle, mut velocity) = (0f32, 0f32);
+        while !identification.is_done() {
+            let current = identification.current().to_num::<f32>();
+            let (sin, cos) = angle.sin_cos();
+            let (alpha, beta) = (-current * sin, current * cos);
+            let phase_currents = [
+                I16F16::from_num(alpha),
+                I16F16::from_num(-alpha / 2. + 3f32.sqrt() / 2. * beta),
+            ];
+            identification.update(
+                &mut foc,
+                phase_currents,
+                I16F16::from_num(angle),
+                I16F16::from_num(velocity * 7.),
+                I16F16::from_num(dt),
+            );
+
+            let acceleration = (torque_constant * current - friction * velocity) / inertia;
+            velocity += acceleration * dt;
+            angle = (angle + velocity * 7. * dt).rem_euclid(core::f32::consts::TAU);
+        }
+
+        let parameters = identification.result().unwrap().clone().unwrap();
+        assert!(
+            parameters.inertia.abs_diff(I16F16::lit("0.02")) < 0.001,
+            "{parameters:?}"
+        );
+        assert!(
+            parameters.viscous_friction.abs_diff(I16F16::lit("0.01")) < 0.001,
+            "{parameters:?}"
+        );
+    }
+
+    #[test]
+    fn mechanical_overflow_is_out_of_range() {
+        let motor = MotorParameters::new(
+            I16F16::lit("0.5"),
+            I16F16::lit("0.2"),
+            I16F16::lit("0.2"),
+            I16F16::from_num(5),
+            7,
+        );
+        let mut identification =
+            MechanicalIdentification::new(&motor, I16F16::ONE, I16F16::ONE, I16F16::ONE);
+
+        // The sums of a long capture at high speed
+        identification.velocity_changes = [I32F32::from_num(3000), I32F32::from_num(-2000)];
+        identification.velocity_integrals =
+            [I32F32::from_num(1_000_000), I32F32::from_num(2_000_000)];
+        identification.torque_integrals = [I32F32::from_num(50), I32F32::ZERO];
+        assert_eq!(
+            identification.calculate(),
+            Err(IdentificationError::OutOfRange)
+        );
+    }
+}
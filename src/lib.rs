@@ -4,15 +4,98 @@
 
 use core::marker::PhantomData;
 
-use fixed::types::I16F16;
+use fixed::types::{I16F16, I32F32};
 
+pub mod angle;
+pub mod current_sense;
+pub mod encoder_calibration;
+pub mod field_weakening;
+pub mod hall;
+pub mod hfi;
+pub mod identification;
+pub mod limiter;
+pub mod motion;
+pub mod motor;
+pub mod mtpa;
+pub mod observer;
 pub mod park_clarke;
 pub mod pid;
+pub mod pll;
+pub mod protection;
 pub mod pwm;
+pub mod six_step;
+pub mod startup;
+pub mod state_machine;
+pub mod thermal;
 
 const FRAC_1_SQRT_3: I16F16 = I16F16::lit("0.57735027");
 const SQRT_3: I16F16 = I16F16::lit("1.7320508");
 
+/// Number of units in one kilo-unit, to convert the parameters given in
+/// milli-units (see [`motor::MotorParameters`]) to SI units.
+pub(crate) const KILO: I16F16 = I16F16::lit("1000");
+
+/// Square root of a value which may be out of the range of [`I16F16`], such as
+/// a sum of squares. Returns zero for negative values, and saturates at
+/// [`I16F16::MAX`].
+fn sqrt_wide(value: I32F32) -> I16F16 {
+    if value <= I32F32::ZERO {
+        return I16F16::ZERO;
+    }
+
+    // The square root of a value with 32 fractional bits has 16 fractional
+    // bits
+    let root = integer_sqrt(value.to_bits() as u64);
+    I16F16::from_bits(i32::try_from(root).unwrap_or(i32::MAX))
+}
+
+/// The magnitude `√(x² + y²)` of a vector, which does not saturate until the
+/// result is out of range.
+fn hypot(x: I16F16, y: I16F16) -> I16F16 {
+    sqrt_wide(square(x).saturating_add(square(y)))
+}
+
+/// The square of a value, without saturating.
+fn square(value: I16F16) -> I32F32 {
+    let value = I32F32::from(value);
+    value * value
+}
+
+/// The largest integer whose square is no more than `value`.
+fn integer_sqrt(value: u64) -> u64 {
+    let mut remainder = value;
+    let mut root = 0u64;
+    let mut bit = 1u64 << 62;
+    while bit > value {
+        bit >>= 2;
+    }
+    while bit != 0 {
+        if remainder >= root + bit {
+            remainder -= root + bit;
+            root = (root >> 1) + bit;
+        } else {
+            root >>= 1;
+        }
+        bit >>= 2;
+    }
+    root
+}
+
+/// First-order low-pass filter step, moving `state` towards `input` with the
+/// given bandwidth (in rad/s) over the time step `dt` (in seconds).
+fn low_pass(state: I16F16, input: I16F16, bandwidth: I16F16, dt: I16F16) -> I16F16 {
+    let alpha = bandwidth.saturating_mul(dt).min(I16F16::ONE);
+    state.saturating_add(alpha.saturating_mul(input.saturating_sub(state)))
+}
+
+/// The magnitude of a d/q voltage as a fraction of the maximum voltage.
+fn voltage_ratio(d: I16F16, q: I16F16, max_voltage: I16F16) -> I16F16 {
+    if max_voltage <= I16F16::ZERO {
+        return I16F16::ZERO;
+    }
+    limiter::magnitude(&park_clarke::RotatingReferenceFrame { d, q }).saturating_div(max_voltage)
+}
+
 /// The Field-Oriented Controller.
 ///
 /// If this controller does not match the exact setup that you desire, then all
@@ -21,29 +104,213 @@ const SQRT_3: I16F16 = I16F16::lit("1.7320508");
 pub struct Foc<Modulator: pwm::Modulation, const PWM_RESOLUTION: u16> {
     flux_current_controller: pid::PIController,
     torque_current_controller: pid::PIController,
+    bus_voltage: I16F16,
+    motor: Option<motor::MotorParameters>,
+    voltage_saturated: bool,
+    current_limit: I16F16,
+    d_current_setpoint: I16F16,
+    voltage_ratio: I16F16,
+    injection_voltage: I16F16,
+    last_rotating_current: park_clarke::RotatingReferenceFrame,
+    orthogonal_current: park_clarke::TwoPhaseReferenceFrame,
+    orthogonal_voltage: park_clarke::TwoPhaseReferenceFrame,
     _phantom: PhantomData<Modulator>,
 }
 
 impl<Modulator: pwm::Modulation, const PWM_RESOLUTION: u16> Foc<Modulator, PWM_RESOLUTION> {
     /// Create a new FOC controller with the desired PI controllers for the flux
     /// and torque components.
+    ///
+    /// The controllers output a voltage in volts, and `bus_voltage` is the DC
+    /// link voltage in volts. This can be updated later with
+    /// [`Foc::set_bus_voltage`].
+    ///
+    /// The output limits of both controllers are managed by the FOC controller,
+    /// which keeps the voltage vector within the linear range of the modulator
+    /// for the current bus voltage so that their anti-windup strategies act on
+    /// the real saturation point.
     pub fn new(
         flux_current_controller: pid::PIController,
         torque_current_controller: pid::PIController,
+        bus_voltage: I16F16,
     ) -> Self {
         Self {
             flux_current_controller,
             torque_current_controller,
+            bus_voltage,
+            motor: None,
+            voltage_saturated: false,
+            current_limit: I16F16::MAX,
+            d_current_setpoint: I16F16::ZERO,
+            voltage_ratio: I16F16::ZERO,
+            injection_voltage: I16F16::ZERO,
+            last_rotating_current: park_clarke::RotatingReferenceFrame {
+                d: I16F16::ZERO,
+                q: I16F16::ZERO,
+            },
+            orthogonal_current: park_clarke::TwoPhaseReferenceFrame {
+                alpha: I16F16::ZERO,
+                beta: I16F16::ZERO,
+            },
+            orthogonal_voltage: park_clarke::TwoPhaseReferenceFrame {
+                alpha: I16F16::ZERO,
+                beta: I16F16::ZERO,
+            },
             _phantom: PhantomData,
         }
     }
 
+    /// Create a new FOC controller for the given motor, with the decoupling
+    /// and back-EMF feed-forward terms enabled.
+    ///
+    /// The current controllers are created with
+    /// [`pid::PIController::current_loop`].
+    ///
+    /// Params:
+    /// - `motor`: the motor parameters
+    /// - `bandwidth`: current loop bandwidth in rad/s
+    /// - `period`: time between updates in seconds
+    /// - `bus_voltage`: DC link voltage in volts
+    pub fn from_motor_parameters(
+        motor: motor::MotorParameters,
+        bandwidth: I16F16,
+        period: I16F16,
+        bus_voltage: I16F16,
+    ) -> Self {
+        let (flux_current_controller, torque_current_controller) = pid::current_loops(
+            motor.resistance,
+            motor.d_inductance,
+            motor.q_inductance,
+            bandwidth,
+            period,
+        );
+        Self::new(
+            flux_current_controller,
+            torque_current_controller,
+            bus_voltage,
+        )
+        .with_motor_parameters(motor)
+    }
+
+    /// Enable the decoupling and back-EMF feed-forward terms using the given
+    /// motor parameters.
+    pub fn with_motor_parameters(mut self, motor: motor::MotorParameters) -> Self {
+        self.motor = Some(motor);
+        self
+    }
+
+    /// Change the motor parameters used for the feed-forward terms, or disable
+    /// them by passing `None`.
+    pub fn set_motor_parameters(&mut self, motor: Option<motor::MotorParameters>) {
+        self.motor = motor;
+    }
+
+    /// The motor parameters used for the feed-forward terms, if any.
+    pub fn motor_parameters(&self) -> Option<&motor::MotorParameters> {
+        self.motor.as_ref()
+    }
+
+    /// Set the measured DC link voltage, in volts.
+    ///
+    /// The voltage commanded by the current controllers is normalised by this
+    /// before modulation, so keeping it up to date compensates for supply sag
+    /// and allows the same gains to be used across supply voltages.
+    pub fn set_bus_voltage(&mut self, bus_voltage: I16F16) {
+        self.bus_voltage = bus_voltage;
+    }
+
+    /// The DC link voltage, in volts.
+    pub fn bus_voltage(&self) -> I16F16 {
+        self.bus_voltage
+    }
+
+    /// Limit the magnitude of the desired current vector, in amps.
+    ///
+    /// The d-axis setpoint has priority, and the desired torque current is
+    /// limited to the remaining headroom.
+    pub fn with_current_limit(mut self, limit: I16F16) -> Self {
+        self.set_current_limit(limit);
+        self
+    }
+
+    /// Change the limit of the magnitude of the desired current vector in
+    /// amps, e.g. from [`thermal::ThermalModel::current_limit`].
+    pub fn set_current_limit(&mut self, limit: I16F16) {
+        self.current_limit = limit.max(I16F16::ZERO);
+    }
+
+    /// The limit of the magnitude of the desired current vector, in amps.
+    pub fn current_limit(&self) -> I16F16 {
+        self.current_limit
+    }
+
+    /// Set the d-axis current setpoint in amps, e.g. from
+    /// [`field_weakening::FieldWeakening`]. Defaults to zero.
+    pub fn set_d_current_setpoint(&mut self, current: I16F16) {
+        self.d_current_setpoint = current;
+    }
+
+    /// The d-axis current setpoint, in amps.
+    pub fn d_current_setpoint(&self) -> I16F16 {
+        self.d_current_setpoint
+    }
+
+    /// Set a voltage in volts to inject on the d-axis on top of the output of
+    /// the current controllers, e.g. from [`hfi::HighFrequencyInjection`].
+    ///
+    /// While this is non-zero, the current controllers act on the average of
+    /// the last two current measurements, which removes the response to an
+    /// injected voltage that alternates every update.
+    pub fn set_injection_voltage(&mut self, voltage: I16F16) {
+        self.injection_voltage = voltage;
+    }
+
+    /// The voltage injected on the d-axis, in volts.
+    pub fn injection_voltage(&self) -> I16F16 {
+        self.injection_voltage
+    }
+
+    /// Reset the current controllers and the stored state, e.g. before
+    /// re-enabling the outputs after they have been disabled.
+    pub fn reset(&mut self) {
+        self.flux_current_controller.reset();
+        self.torque_current_controller.reset();
+        self.voltage_saturated = false;
+        self.voltage_ratio = I16F16::ZERO;
+        self.last_rotating_current = park_clarke::RotatingReferenceFrame {
+            d: I16F16::ZERO,
+            q: I16F16::ZERO,
+        };
+        self.orthogonal_current = park_clarke::TwoPhaseReferenceFrame {
+            alpha: I16F16::ZERO,
+            beta: I16F16::ZERO,
+        };
+        self.orthogonal_voltage = park_clarke::TwoPhaseReferenceFrame {
+            alpha: I16F16::ZERO,
+            beta: I16F16::ZERO,
+        };
+    }
+
+    /// Reset the controller and return the PWM values for a zero voltage
+    /// vector, e.g. in response to a [`protection::Fault`] when the outputs
+    /// cannot be disabled.
+    pub fn zero_output(&mut self) -> [u16; 3] {
+        self.reset();
+        Modulator::as_compare_value::<PWM_RESOLUTION>(park_clarke::TwoPhaseReferenceFrame {
+            alpha: I16F16::ZERO,
+            beta: I16F16::ZERO,
+        })
+    }
+
     /// Update the FOC controller with the current state of the motor.
     ///
     /// Params:
     /// - `currents`: phase currents in amps
     /// - `angle`: electrical angle in radians
-    /// - `desired_torque`: amps
+    /// - `velocity`: electrical velocity in rad/s, only used for the
+    ///   feed-forward terms when motor parameters are set
+    /// - `desired_torque`: q-axis current in amps, limited to the headroom
+    ///   left by the d-axis setpoint within the current limit
     /// - `dt`: time delta since last update, in units consistent with the PI gain units.
     ///
     /// Returns:
@@ -52,11 +319,69 @@ impl<Modulator: pwm::Modulation, const PWM_RESOLUTION: u16> Foc<Modulator, PWM_R
         &mut self,
         currents: [I16F16; 2],
         angle: I16F16,
+        velocity: I16F16,
         desired_torque: I16F16,
         dt: I16F16,
+    ) -> [u16; 3] {
+        let setpoint = park_clarke::RotatingReferenceFrame {
+            d: self.d_current_setpoint,
+            q: desired_torque,
+        };
+        self.update_currents(currents, angle, velocity, setpoint, dt)
+    }
+
+    /// Update the FOC controller with a torque to produce, using a reference
+    /// generator such as [`mtpa::Mtpa`] to choose the d/q current.
+    ///
+    /// The d-axis setpoint (see [`Foc::set_d_current_setpoint`]) is used
+    /// instead of the d-axis current from the reference when it is more
+    /// negative, e.g. from [`field_weakening::FieldWeakening`] above the base
+    /// speed, and the q-axis current is recalculated to keep the torque.
+    ///
+    /// Params:
+    /// - `currents`: phase currents in amps
+    /// - `angle`: electrical angle in radians
+    /// - `velocity`: electrical velocity in rad/s, only used for the
+    ///   feed-forward terms when motor parameters are set
+    /// - `torque`: torque in N·m, which is reduced if the current is limited
+    /// - `reference`: generates the d/q current for the torque
+    /// - `dt`: time delta since last update, in units consistent with the PI gain units.
+    ///
+    /// Returns:
+    /// - The 3 PWM values to be set on your timer channels.
+    pub fn update_torque(
+        &mut self,
+        currents: [I16F16; 2],
+        angle: I16F16,
+        velocity: I16F16,
+        torque: I16F16,
+        reference: &impl mtpa::TorqueReference,
+        dt: I16F16,
+    ) -> [u16; 3] {
+        let setpoint = reference.weakened_currents(torque, self.d_current_setpoint);
+        self.update_currents(currents, angle, velocity, setpoint, dt)
+    }
+
+    /// Run the current controllers to the given d/q current setpoint.
+    fn update_currents(
+        &mut self,
+        currents: [I16F16; 2],
+        angle: I16F16,
+        velocity: I16F16,
+        setpoint: park_clarke::RotatingReferenceFrame,
+        dt: I16F16,
     ) -> [u16; 3] {
         let (sin_angle, cos_angle) = cordic::sin_cos(angle);
 
+        // Limit the current vector to a circle with priority given to the
+        // d-axis. The default limit is treated as unlimited.
+        let (d_setpoint, desired_torque) = if self.current_limit < I16F16::MAX {
+            let (limited, _) = limiter::limit(setpoint, self.current_limit);
+            (limited.d, limited.q)
+        } else {
+            (setpoint.d, setpoint.q)
+        };
+
         // Clarke transform
         let orthogonal_current =
             park_clarke::clarke(park_clarke::ThreePhaseBalancedReferenceFrame {
@@ -65,24 +390,318 @@ impl<Modulator: pwm::Modulation, const PWM_RESOLUTION: u16> Foc<Modulator, PWM_R
             });
 
         // Park transform
-        let rotating_current = park_clarke::park(cos_angle, sin_angle, orthogonal_current);
+        let rotating_current = park_clarke::park(cos_angle, sin_angle, orthogonal_current.clone());
+        self.orthogonal_current = orthogonal_current;
 
-        // Current PI controllers
+        // Remove the response to the injected voltage
+        let controlled_current = if self.injection_voltage != I16F16::ZERO {
+            park_clarke::RotatingReferenceFrame {
+                d: (rotating_current.d / 2).saturating_add(self.last_rotating_current.d / 2),
+                q: (rotating_current.q / 2).saturating_add(self.last_rotating_current.q / 2),
+            }
+        } else {
+            rotating_current.clone()
+        };
+        self.last_rotating_current = rotating_current;
+
+        // Decoupling and back-EMF feed-forward
+        let feed_forward = match &self.motor {
+            Some(motor) => motor.decoupling_voltage(velocity, &controlled_current),
+            None => park_clarke::RotatingReferenceFrame {
+                d: I16F16::ZERO,
+                q: I16F16::ZERO,
+            },
+        };
+
+        // Current PI controllers. The voltage vector (including feed-forward)
+        // is limited to a circle with priority given to the d-axis, and the
+        // q-axis controller is limited to the remaining headroom.
+        let max_voltage = Modulator::max_voltage(self.bus_voltage).max(I16F16::ZERO);
+        let d_offset = feed_forward.d.saturating_add(self.injection_voltage);
+        self.flux_current_controller.set_output_limits(
+            (-max_voltage).saturating_sub(d_offset),
+            max_voltage.saturating_sub(d_offset),
+        );
         let v_d = self
             .flux_current_controller
-            .update(rotating_current.d, I16F16::ZERO, dt);
+            .update(controlled_current.d, d_setpoint, dt)
+            .saturating_add(d_offset);
+        let v_q_max = limiter::q_headroom(v_d, max_voltage);
+        self.torque_current_controller.set_output_limits(
+            (-v_q_max).saturating_sub(feed_forward.q),
+            v_q_max.saturating_sub(feed_forward.q),
+        );
         let v_q = self
             .torque_current_controller
-            .update(rotating_current.q, desired_torque, dt);
+            .update(controlled_current.q, desired_torque, dt)
+            .saturating_add(feed_forward.q);
+        self.voltage_saturated = self.flux_current_controller.is_saturated()
+            || self.torque_current_controller.is_saturated();
+        self.voltage_ratio = voltage_ratio(v_d, v_q, max_voltage);
 
-        // Inverse Park transform
-        let orthogonal_voltage = park_clarke::inverse_park(
+        self.modulate(
             cos_angle,
             sin_angle,
             park_clarke::RotatingReferenceFrame { d: v_d, q: v_q },
-        );
+            max_voltage,
+        )
+    }
+
+    /// Update the FOC controller with a voltage to apply directly, bypassing
+    /// the current controllers, e.g. for a V/f start-up.
+    ///
+    /// The currents are still measured, and the current controllers are reset
+    /// to output the applied voltage so that switching back to
+    /// [`Foc::update`] does not cause a step in the output.
+    ///
+    /// Params:
+    /// - `currents`: phase currents in amps
+    /// - `angle`: electrical angle in radians
+    /// - `velocity`: electrical velocity in rad/s, only used for the
+    ///   feed-forward terms when motor parameters are set
+    /// - `voltage`: d/q voltage in volts, limited to the linear range of the
+    ///   modulator
+    ///
+    /// Returns:
+    /// - The 3 PWM values to be set on your timer channels.
+    pub fn update_voltage(
+        &mut self,
+        currents: [I16F16; 2],
+        angle: I16F16,
+        velocity: I16F16,
+        voltage: park_clarke::RotatingReferenceFrame,
+    ) -> [u16; 3] {
+        let (sin_angle, cos_angle) = cordic::sin_cos(angle);
+
+        let orthogonal_current =
+            park_clarke::clarke(park_clarke::ThreePhaseBalancedReferenceFrame {
+                a: currents[0],
+                b: currents[1],
+            });
+        let rotating_current = park_clarke::park(cos_angle, sin_angle, orthogonal_current.clone());
+        self.orthogonal_current = orthogonal_current;
+
+        let max_voltage = Modulator::max_voltage(self.bus_voltage).max(I16F16::ZERO);
+        let (voltage, saturated) = limiter::limit(voltage, max_voltage);
+        self.voltage_saturated = saturated;
+        self.voltage_ratio = voltage_ratio(voltage.d, voltage.q, max_voltage);
+
+        // Preload the controllers with the applied voltage, less the
+        // feed-forward that would be added to their output
+        let feed_forward = match &self.motor {
+            Some(motor) => motor.decoupling_voltage(velocity, &rotating_current),
+            None => park_clarke::RotatingReferenceFrame {
+                d: I16F16::ZERO,
+                q: I16F16::ZERO,
+            },
+        };
+        self.flux_current_controller
+            .reset_to(voltage.d.saturating_sub(feed_forward.d));
+        self.torque_current_controller
+            .reset_to(voltage.q.saturating_sub(feed_forward.q));
+        self.last_rotating_current = rotating_current;
+
+        self.modulate(cos_angle, sin_angle, voltage, max_voltage)
+    }
+
+    /// Transform the rotating frame voltage to the stationary frame and
+    /// modulate it to PWM values.
+    fn modulate(
+        &mut self,
+        cos_angle: I16F16,
+        sin_angle: I16F16,
+        voltage: park_clarke::RotatingReferenceFrame,
+        max_voltage: I16F16,
+    ) -> [u16; 3] {
+        // Inverse Park transform
+        let orthogonal_voltage = park_clarke::inverse_park(cos_angle, sin_angle, voltage);
+
+        // Normalise to the range of the modulator
+        let normalised_voltage = if max_voltage > I16F16::ZERO {
+            park_clarke::TwoPhaseReferenceFrame {
+                alpha: orthogonal_voltage.alpha / max_voltage,
+                beta: orthogonal_voltage.beta / max_voltage,
+            }
+        } else {
+            park_clarke::TwoPhaseReferenceFrame {
+                alpha: I16F16::ZERO,
+                beta: I16F16::ZERO,
+            }
+        };
+        self.orthogonal_voltage = orthogonal_voltage;
 
         // Modulate the result to PWM values
-        Modulator::as_compare_value::<PWM_RESOLUTION>(orthogonal_voltage)
+        Modulator::as_compare_value::<PWM_RESOLUTION>(normalised_voltage)
+    }
+
+    /// The magnitude of the voltage vector commanded by the last update, as a
+    /// fraction of the maximum voltage of the modulator.
+    ///
+    /// This approaches one as the back-EMF approaches the bus voltage, e.g.
+    /// for [`field_weakening::FieldWeakening`].
+    pub fn voltage_ratio(&self) -> I16F16 {
+        self.voltage_ratio
+    }
+
+    /// Whether the voltage vector was limited during the last update.
+    ///
+    /// If this is continuously true then the requested current cannot be
+    /// reached with the available voltage.
+    pub fn is_voltage_saturated(&self) -> bool {
+        self.voltage_saturated
+    }
+
+    /// The rotating frame current measured during the last update, in amps,
+    /// e.g. for a [`thermal::ThermalModel`].
+    pub fn rotating_current(&self) -> &park_clarke::RotatingReferenceFrame {
+        &self.last_rotating_current
+    }
+
+    /// The stationary frame current measured during the last update, in amps.
+    pub fn orthogonal_current(&self) -> &park_clarke::TwoPhaseReferenceFrame {
+        &self.orthogonal_current
+    }
+
+    /// The stationary frame voltage commanded by the last update, in volts.
+    ///
+    /// This is the voltage that is applied until the next update, e.g. for use
+    /// with an [`observer`].
+    pub fn orthogonal_voltage(&self) -> &park_clarke::TwoPhaseReferenceFrame {
+        &self.orthogonal_voltage
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A proportional controller, which outputs `gain` per amp of error.
+    fn proportional(gain: i32) -> pid::PIController {
+        pid::PIController::new(I16F16::from_num(-gain), I16F16::ZERO)
+    }
+
+    /// The phase currents for a d-axis current at an angle of zero.
+    fn d_currents(d: I16F16) -> [I16F16; 2] {
+        [d, -d / 2]
+    }
+
+    fn assert_pwm(pwm: [u16; 3], expected: [u16; 3]) {
+        for (value, expected) in pwm.into_iter().zip(expected) {
+            assert!(value.abs_diff(expected) <= 1, "{pwm:?} != {expected:?}");
+        }
+    }
+
+    #[test]
+    fn voltage_is_limited_with_d_axis_priority() {
+        // A 2 V bus gives ±1 V with sinusoidal modulation
+        let mut foc: Foc<pwm::Sinusoidal, 1000> =
+            Foc::new(proportional(5), proportional(5), I16F16::from_num(2));
+        let dt = I16F16::from_num(1. / 16384.);
+
+        // Within the available voltage the output is unchanged, with -0.5 V on
+        // the d-axis and 0.5 V on the q-axis
+        let currents = d_currents(I16F16::lit("0.1"));
+        let pwm = foc.update(currents, I16F16::ZERO, I16F16::ZERO, I16F16::lit("0.1"), dt);
+        assert_pwm(pwm, [250, 842, 409]);
+        assert!(!foc.is_voltage_saturated());
+
+        // The q-axis is limited to the headroom left by the d-axis
+        let pwm = foc.update(currents, I16F16::ZERO, I16F16::ZERO, I16F16::ONE, dt);
+        assert_pwm(pwm, [250, 1000, 250]);
+        assert!(foc.is_voltage_saturated());
+
+        // The d-axis alone can use all of the voltage
+        let pwm = foc.update(
+            d_currents(I16F16::ONE),
+            I16F16::ZERO,
+            I16F16::ZERO,
+            I16F16::ONE,
+            dt,
+        );
+        assert_pwm(pwm, [0, 751, 751]);
+
+        // A d-axis setpoint is controlled in the same way, and the voltage
+        // ratio is the magnitude as a fraction of the available voltage
+        foc.set_d_current_setpoint(I16F16::lit("-0.1"));
+        let currents = [I16F16::ZERO; 2];
+        let pwm = foc.update(currents, I16F16::ZERO, I16F16::ZERO, I16F16::lit("0.1"), dt);
+        assert_pwm(pwm, [250, 842, 409]);
+        assert!(foc.voltage_ratio().abs_diff(I16F16::lit("0.7071")) < 0.001);
+    }
+
+    #[test]
+    fn output_scales_with_bus_voltage() {
+        let mut foc: Foc<pwm::Sinusoidal, 1000> =
+            Foc::new(proportional(1), proportional(1), I16F16::from_num(24));
+        let dt = I16F16::from_num(1. / 16384.);
+
+        // 3 V on the q-axis is a quarter of the 12 V available
+        let currents = [I16F16::ZERO; 2];
+        let pwm = foc.update(
+            currents,
+            I16F16::ZERO,
+            I16F16::ZERO,
+            I16F16::from_num(3),
+            dt,
+        );
+        assert_pwm(pwm, [500, 609, 392]);
+        assert!(foc.voltage_ratio().abs_diff(I16F16::lit("0.25")) < 0.001);
+
+        // The same voltage is twice the duty at half the bus voltage
+        foc.set_bus_voltage(I16F16::from_num(12));
+        let pwm = foc.update(
+            currents,
+            I16F16::ZERO,
+            I16F16::ZERO,
+            I16F16::from_num(3),
+            dt,
+        );
+        assert_pwm(pwm, [500, 717, 284]);
+        assert!(foc.voltage_ratio().abs_diff(I16F16::lit("0.5")) < 0.001);
+        assert!(!foc.is_voltage_saturated());
+
+        // Above the available voltage the output is limited
+        foc.set_bus_voltage(I16F16::from_num(4));
+        let pwm = foc.update(
+            currents,
+            I16F16::ZERO,
+            I16F16::ZERO,
+            I16F16::from_num(3),
+            dt,
+        );
+        assert_pwm(pwm, [500, 934, 67]);
+        assert!(foc.is_voltage_saturated());
+    }
+
+    #[test]
+    fn feed_forward_from_motor_parameters() {
+        let motor = motor::MotorParameters::new(
+            I16F16::lit("0.1"),
+            I16F16::lit("0.2"),
+            I16F16::lit("0.3"),
+            I16F16::from_num(5),
+            7,
+        );
+        // Without any gain the output is only the feed-forward
+        let controller = || pid::PIController::new(I16F16::ZERO, I16F16::ZERO);
+        let mut foc: Foc<pwm::Sinusoidal, 1000> =
+            Foc::new(controller(), controller(), I16F16::from_num(24));
+        let dt = I16F16::from_num(1. / 16384.);
+
+        // 1 A on the d-axis and 2 A on the q-axis at an angle of zero
+        let currents = [I16F16::ONE, I16F16::lit("1.2320508")];
+        let velocity = I16F16::from_num(1000);
+        let pwm = foc.update(currents, I16F16::ZERO, velocity, I16F16::ZERO, dt);
+        assert_pwm(pwm, [500, 500, 500]);
+
+        // The cross-coupling is -ω·Lq·iq = -0.6 V on the d-axis, and the
+        // back-EMF and cross-coupling is ω·(Ld·id + ψ) = 5.2 V on the q-axis
+        foc.set_motor_parameters(Some(motor));
+        let pwm = foc.update(currents, I16F16::ZERO, velocity, I16F16::ZERO, dt);
+        assert_pwm(pwm, [475, 701, 325]);
+
+        // The feed-forward changes sign with the velocity
+        let pwm = foc.update(currents, I16F16::ZERO, -velocity, I16F16::ZERO, dt);
+        assert_pwm(pwm, [526, 300, 676]);
     }
 }
@@ -0,0 +1,105 @@
+//! Magnitude limiting of vectors in the rotating reference frame.
+//!
+//! Limiting the d and q components independently (or letting the modulator
+//! clip each phase) distorts the direction of the vector. The functions here
+//! instead limit the vector to a circle, giving priority to the d-axis so
+//! that flux control is maintained and the q-axis gets whatever headroom is
+//! left.
+
+use fixed::types::I16F16;
+
+use crate::park_clarke::RotatingReferenceFrame;
+
+/// The magnitude available on the q-axis, given the d-axis component and the
+/// maximum magnitude of the vector.
+///
+/// Returns zero if the d-axis component alone exceeds `max`.
+pub fn q_headroom(d: I16F16, max: I16F16) -> I16F16 {
+    crate::sqrt_wide(crate::square(max) - crate::square(d))
+}
+
+/// Limit the magnitude of a vector to `max`, giving priority to the d-axis.
+///
+/// The d-axis component is clamped to `±max`, and the q-axis component is
+/// clamped to the remaining headroom. Returns the limited vector, and whether
+/// any limiting was applied.
+pub fn limit(value: RotatingReferenceFrame, max: I16F16) -> (RotatingReferenceFrame, bool) {
+    let d = value.d.clamp(-max, max);
+    let q_max = q_headroom(d, max);
+    let q = value.q.clamp(-q_max, q_max);
+    let saturated = d != value.d || q != value.q;
+
+    (RotatingReferenceFrame { d, q }, saturated)
+}
+
+/// The magnitude of a vector in the rotating reference frame.
+pub fn magnitude(value: &RotatingReferenceFrame) -> I16F16 {
+    crate::hypot(value.d, value.q)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn within_limit_is_unchanged() {
+        let value = RotatingReferenceFrame {
+            d: I16F16::from_num(3),
+            q: I16F16::from_num(-4),
+        };
+        let (limited, saturated) = limit(value.clone(), I16F16::from_num(5));
+        assert!(!saturated);
+        assert_eq!(limited.d, value.d);
+        assert_eq!(limited.q, value.q);
+    }
+
+    #[test]
+    fn d_axis_has_priority() {
+        let value = RotatingReferenceFrame {
+            d: I16F16::from_num(-3),
+            q: I16F16::from_num(10),
+        };
+        let (limited, saturated) = limit(value, I16F16::from_num(5));
+        assert!(saturated);
+        assert_eq!(limited.d, -3);
+        assert!(limited.q.abs_diff(I16F16::from_num(4)) < 0.001);
+        assert!(magnitude(&limited) <= 5);
+
+        let value = RotatingReferenceFrame {
+            d: I16F16::from_num(8),
+            q: I16F16::from_num(1),
+        };
+        let (limited, _) = limit(value, I16F16::from_num(5));
+        assert_eq!(limited.d, 5);
+        assert_eq!(limited.q, 0);
+    }
+
+    #[test]
+    fn large_vectors() {
+        let value = RotatingReferenceFrame {
+            d: I16F16::from_num(-300),
+            q: I16F16::from_num(400),
+        };
+        assert!(magnitude(&value).abs_diff(I16F16::from_num(500)) < 0.001);
+
+        let (limited, saturated) = limit(value, I16F16::from_num(250));
+        assert!(saturated);
+        assert_eq!(limited.d, -250);
+        assert_eq!(limited.q, 0);
+
+        let value = RotatingReferenceFrame {
+            d: I16F16::from_num(-150),
+            q: I16F16::from_num(400),
+        };
+        let (limited, _) = limit(value, I16F16::from_num(250));
+        assert_eq!(limited.d, -150);
+        assert!(limited.q.abs_diff(I16F16::from_num(200)) < 0.001);
+
+        // The magnitude saturates once it is out of range
+        let value = RotatingReferenceFrame {
+            d: I16F16::MAX,
+            q: I16F16::MIN,
+        };
+        assert_eq!(magnitude(&value), I16F16::MAX);
+    }
+}
@@ -0,0 +1,462 @@
+//! Cascaded velocity and position control on top of [`Foc`].
+//!
+//! The position loop outputs a velocity setpoint, the velocity loop outputs a
+//! q-axis current setpoint, and [`Foc`] runs the current loops. The outer
+//! loops can be run at a fraction of the current loop rate using
+//! [`MotionController::with_loop_dividers`].
+
+use fixed::types::I16F16;
+
+use crate::{limiter, pid, pwm, Foc};
+
+/// The quantity being controlled by a [`MotionController`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlMode {
+    /// The setpoint is a q-axis current, in amps.
+    Torque,
+    /// The setpoint is a mechanical velocity, in rad/s.
+    Velocity,
+    /// The setpoint is a mechanical position, in radians.
+    Position,
+}
+
+/// A motion controller with selectable torque, velocity, or position control.
+pub struct MotionController<Modulator: pwm::Modulation, const PWM_RESOLUTION: u16> {
+    foc: Foc<Modulator, PWM_RESOLUTION>,
+    velocity_controller: pid::PIController,
+    position_controller: pid::PIDController,
+    mode: ControlMode,
+    setpoint: I16F16,
+    velocity_limit: I16F16,
+    velocity_divider: LoopDivider,
+    position_divider: LoopDivider,
+    velocity_setpoint: I16F16,
+    current_setpoint: I16F16,
+    last_velocity: I16F16,
+    last_position: I16F16,
+}
+
+impl<Modulator: pwm::Modulation, const PWM_RESOLUTION: u16>
+    MotionController<Modulator, PWM_RESOLUTION>
+{
+    /// Create a new motion controller in torque mode, with a setpoint of zero.
+    ///
+    /// The velocity controller takes a velocity in rad/s and outputs a current
+    /// in amps, and the position controller takes a position in radians and
+    /// outputs a velocity in rad/s. Their output limits are managed by the
+    /// motion controller: the current is limited by the current limit of the
+    /// [`Foc`] (see [`Foc::set_current_limit`]), and the velocity by
+    /// [`MotionController::with_velocity_limit`].
+    pub fn new(
+        foc: Foc<Modulator, PWM_RESOLUTION>,
+        velocity_controller: pid::PIController,
+        position_controller: pid::PIDController,
+    ) -> Self {
+        let mut controller = Self {
+            foc,
+            velocity_controller,
+            position_controller,
+            mode: ControlMode::Torque,
+            setpoint: I16F16::ZERO,
+            velocity_limit: I16F16::MAX,
+            velocity_divider: LoopDivider::new(1),
+            position_divider: LoopDivider::new(1),
+            velocity_setpoint: I16F16::ZERO,
+            current_setpoint: I16F16::ZERO,
+            last_velocity: I16F16::ZERO,
+            last_position: I16F16::ZERO,
+        };
+        controller.apply_current_limit();
+        controller.set_velocity_limit(I16F16::MAX);
+        controller
+    }
+
+    /// Run the velocity loop once every `velocity` updates, and the position
+    /// loop once every `position` updates.
+    ///
+    /// Both dividers default to 1, i.e. every loop runs on every update.
+    pub fn with_loop_dividers(mut self, velocity: u16, position: u16) -> Self {
+        self.velocity_divider = LoopDivider::new(velocity);
+        self.position_divider = LoopDivider::new(position);
+        self
+    }
+
+    /// Set the initial maximum velocity magnitude, in rad/s.
+    pub fn with_velocity_limit(mut self, velocity_limit: I16F16) -> Self {
+        self.set_velocity_limit(velocity_limit);
+        self
+    }
+
+    /// Change the maximum velocity magnitude, in rad/s.
+    pub fn set_velocity_limit(&mut self, velocity_limit: I16F16) {
+        self.velocity_limit = velocity_limit.saturating_abs();
+        self.position_controller
+            .set_output_limits(-self.velocity_limit, self.velocity_limit);
+    }
+
+    /// Limit the velocity controller to the q-axis current left by the d-axis
+    /// setpoint within the current limit of the [`Foc`], returning the limit.
+    fn apply_current_limit(&mut self) -> I16F16 {
+        let limit = limiter::q_headroom(self.foc.d_current_setpoint(), self.foc.current_limit());
+        self.velocity_controller.set_output_limits(-limit, limit);
+        limit
+    }
+
+    /// The active control mode.
+    pub fn mode(&self) -> ControlMode {
+        self.mode
+    }
+
+    /// Switch to a different control mode.
+    ///
+    /// The setpoint is set to hold the present state (the last current
+    /// setpoint, velocity, or position), and the controllers of the newly
+    /// active loops are preloaded with the present command, so that the output
+    /// does not jump on the switch.
+    pub fn set_mode(&mut self, mode: ControlMode) {
+        if mode == self.mode {
+            return;
+        }
+
+        match mode {
+            ControlMode::Torque => {
+                self.setpoint = self.current_setpoint;
+            }
+            ControlMode::Velocity => {
+                self.setpoint = self.last_velocity;
+                self.velocity_controller.reset_to(self.current_setpoint);
+            }
+            ControlMode::Position => {
+                self.setpoint = self.last_position;
+                self.velocity_setpoint = self.last_velocity;
+                self.position_controller.reset_to(self.last_velocity);
+                if self.mode == ControlMode::Torque {
+                    self.velocity_controller.reset_to(self.current_setpoint);
+                }
+            }
+        }
+
+        self.velocity_divider.reset();
+        self.position_divider.reset();
+        self.mode = mode;
+    }
+
+    /// The setpoint of the active control mode.
+    pub fn setpoint(&self) -> I16F16 {
+        self.setpoint
+    }
+
+    /// The velocity setpoint from the last update, in rad/s.
+    ///
+    /// In position mode this is the output of the position loop.
+    pub fn velocity_setpoint(&self) -> I16F16 {
+        self.velocity_setpoint
+    }
+
+    /// The q-axis current setpoint from the last update, in amps.
+    pub fn current_setpoint(&self) -> I16F16 {
+        self.current_setpoint
+    }
+
+    /// Set the setpoint of the active control mode.
+    ///
+    /// The units depend on the mode, see [`ControlMode`].
+    pub fn set_setpoint(&mut self, setpoint: I16F16) {
+        self.setpoint = setpoint;
+    }
+
+    /// The underlying FOC controller.
+    pub fn foc(&self) -> &Foc<Modulator, PWM_RESOLUTION> {
+        &self.foc
+    }
+
+    /// The underlying FOC controller.
+    pub fn foc_mut(&mut self) -> &mut Foc<Modulator, PWM_RESOLUTION> {
+        &mut self.foc
+    }
+
+    /// Update the motion controller with the current state of the motor.
+    ///
+    /// Params:
+    /// - `currents`: phase currents in amps
+    /// - `angle`: electrical angle in radians
+    /// - `velocity`: mechanical velocity in rad/s
+    /// - `position`: mechanical position in radians
+    /// - `dt`: time delta since last update, in units consistent with the PI gain units.
+    ///
+    /// Returns:
+    /// - The 3 PWM values to be set on your timer channels.
+    pub fn update(
+        &mut self,
+        currents: [I16F16; 2],
+        angle: I16F16,
+        velocity: I16F16,
+        position: I16F16,
+        dt: I16F16,
+    ) -> [u16; 3] {
+        self.last_velocity = velocity;
+        self.last_position = position;
+        let current_limit = self.apply_current_limit();
+
+        if self.mode == ControlMode::Position {
+            if let Some(dt) = self.position_divider.tick(dt) {
+                self.velocity_setpoint =
+                    self.position_controller.update(position, self.setpoint, dt);
+            }
+        }
+
+        match self.mode {
+            ControlMode::Torque => {
+                self.current_setpoint = self.setpoint.clamp(-current_limit, current_limit);
+            }
+            ControlMode::Velocity | ControlMode::Position => {
+                if self.mode == ControlMode::Velocity {
+                    self.velocity_setpoint = self
+                        .setpoint
+                        .clamp(-self.velocity_limit, self.velocity_limit);
+                }
+                if let Some(dt) = self.velocity_divider.tick(dt) {
+                    self.current_setpoint =
+                        self.velocity_controller
+                            .update(velocity, self.velocity_setpoint, dt);
+                }
+            }
+        }
+
+        let electrical_velocity = self
+            .foc
+            .motor_parameters()
+            .map(|motor| velocity.saturating_mul_int(motor.pole_pairs.into()))
+            .unwrap_or(I16F16::ZERO);
+        self.foc.update(
+            currents,
+            angle,
+            electrical_velocity,
+            self.current_setpoint,
+            dt,
+        )
+    }
+}
+
+/// Runs a loop once every `divider` ticks, accumulating the time between runs.
+struct LoopDivider {
+    divider: u16,
+    count: u16,
+    elapsed: I16F16,
+}
+
+impl LoopDivider {
+    fn new(divider: u16) -> Self {
+        Self {
+            divider: divider.max(1),
+            count: 0,
+            elapsed: I16F16::ZERO,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.count = 0;
+        self.elapsed = I16F16::ZERO;
+    }
+
+    /// Returns the time since the loop last ran if it should run on this tick.
+    fn tick(&mut self, dt: I16F16) -> Option<I16F16> {
+        self.count += 1;
+        self.elapsed = self.elapsed.saturating_add(dt);
+
+        if self.count < self.divider {
+            return None;
+        }
+
+        let elapsed = self.elapsed;
+        self.reset();
+        Some(elapsed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Torque per amp divided by the inertia, in rad/s² per amp.
+    const ACCELERATION_PER_AMP: f32 = 100.;
+
+    /// A motion controller whose current controllers output one volt per amp
+    /// of setpoint while the measured current is zero, so the q-axis voltage
+    /// at an angle of zero is the current setpoint.
+    fn controller(
+        velocity: pid::PIController,
+        position: pid::PIDController,
+    ) -> MotionController<pwm::SpaceVector, 1000> {
+        let current = || pid::PIController::new(-I16F16::ONE, I16F16::ZERO);
+        let foc = Foc::new(current(), current(), I16F16::from_num(100));
+        MotionController::new(foc, velocity, position)
+    }
+
+    fn velocity_controller() -> pid::PIController {
+        pid::PIController::new(I16F16::lit("-0.2"), I16F16::lit("-2"))
+    }
+
+    /// Run the controller against an inertia for `updates` updates of `dt`
+    /// seconds, updating the `(velocity, position)` state.
+    fn run(
+        motion: &mut MotionController<pwm::SpaceVector, 1000>,
+        state: &mut (f32, f32),
+        updates: usize,
+        dt: f32,
+    ) {
+        for _ in 0..updates {
+            motion.update(
+                [I16F16::ZERO; 2],
+                I16F16::ZERO,
+                I16F16::from_num(state.0),
+                I16F16::from_num(state.1),
+                I16F16::from_num(dt),
+            );
+            let current = motion.foc().orthogonal_voltage().beta.to_num::<f32>();
+            state.0 += current * ACCELERATION_PER_AMP * dt;
+            state.1 += state.0 * dt;
+        }
+    }
+
+    #[test]
+    fn tracks_velocity() {
+        let position = pid::PIDController::new(I16F16::ZERO, I16F16::ZERO, I16F16::ZERO);
+        let mut motion = controller(velocity_controller(), position);
+        motion.set_mode(ControlMode::Velocity);
+        motion.set_setpoint(I16F16::from_num(10));
+
+        let mut state = (0., 0.);
+        run(&mut motion, &mut state, 1024, 1. / 1024.);
+        assert!((state.0 - 10.).abs() < 0.1, "{state:?}");
+        assert!(motion.current_setpoint().abs() < 0.05);
+    }
+
+    #[test]
+    fn tracks_position() {
+        let position = pid::PIDController::new(I16F16::from_num(-5), I16F16::ZERO, I16F16::ZERO);
+        let mut motion =
+            controller(velocity_controller(), position).with_velocity_limit(I16F16::from_num(2));
+        motion.set_mode(ControlMode::Position);
+        motion.set_setpoint(I16F16::from_num(3));
+
+        // The velocity is limited while far from the target
+        let mut state = (0., 0.);
+        run(&mut motion, &mut state, 512, 1. / 1024.);
+        assert_eq!(motion.velocity_setpoint(), 2);
+        assert!((state.0 - 2.).abs() < 0.1, "{state:?}");
+
+        run(&mut motion, &mut state, 4096, 1. / 1024.);
+        assert!((state.1 - 3.).abs() < 0.01, "{state:?}");
+        assert!(state.0.abs() < 0.05, "{state:?}");
+    }
+
+    #[test]
+    fn velocity_limit_is_a_magnitude() {
+        let position = pid::PIDController::new(I16F16::from_num(-5), I16F16::ZERO, I16F16::ZERO);
+        let mut motion =
+            controller(velocity_controller(), position).with_velocity_limit(I16F16::from_num(-2));
+        motion.set_mode(ControlMode::Velocity);
+        motion.set_setpoint(I16F16::from_num(10));
+
+        let mut state = (0., 0.);
+        run(&mut motion, &mut state, 1, 1. / 1024.);
+        assert_eq!(motion.velocity_setpoint(), 2);
+
+        motion.set_velocity_limit(I16F16::MIN);
+        run(&mut motion, &mut state, 1, 1. / 1024.);
+        assert_eq!(motion.velocity_setpoint(), 10);
+
+        // The position controller is limited in the same way
+        motion.set_velocity_limit(I16F16::from_num(-2));
+        motion.set_mode(ControlMode::Position);
+        motion.set_setpoint(I16F16::from_num(-100));
+        run(&mut motion, &mut state, 1, 1. / 1024.);
+        assert_eq!(motion.velocity_setpoint(), -2);
+    }
+
+    #[test]
+    fn loop_dividers() {
+        let mut divider = LoopDivider::new(4);
+        let dt = I16F16::lit("0.25");
+        for _ in 0..3 {
+            for _ in 0..3 {
+                assert_eq!(divider.tick(dt), None);
+            }
+            assert_eq!(divider.tick(dt), Some(I16F16::ONE));
+        }
+        assert_eq!(LoopDivider::new(0).tick(dt), Some(dt));
+
+        // The velocity loop only updates its output every 4 updates
+        let position = pid::PIDController::new(I16F16::ZERO, I16F16::ZERO, I16F16::ZERO);
+        let mut motion = controller(velocity_controller(), position).with_loop_dividers(4, 1);
+        motion.set_mode(ControlMode::Velocity);
+        motion.set_setpoint(I16F16::from_num(10));
+        let mut last = motion.current_setpoint();
+        let mut changes = 0;
+        let mut state = (0., 0.);
+        for _ in 0..64 {
+            run(&mut motion, &mut state, 1, 1. / 1024.);
+            if motion.current_setpoint() != last {
+                changes += 1;
+                last = motion.current_setpoint();
+            }
+        }
+        assert_eq!(changes, 16);
+    }
+
+    #[test]
+    fn mode_switches_are_bumpless() {
+        let position = pid::PIDController::new(I16F16::from_num(-5), I16F16::ZERO, I16F16::ZERO);
+        let mut motion = controller(velocity_controller(), position);
+        let dt = 1. / 1024.;
+
+        // Accelerate in torque mode
+        motion.set_setpoint(I16F16::lit("0.05"));
+        let mut state = (0., 0.);
+        run(&mut motion, &mut state, 256, dt);
+
+        // Velocity mode holds the velocity with the same current
+        motion.set_mode(ControlMode::Velocity);
+        // The setpoint is the velocity from the last update, a step behind
+        assert!(motion.setpoint().abs_diff(I16F16::from_num(state.0)) < 0.01);
+        run(&mut motion, &mut state, 1, dt);
+        assert!(motion.current_setpoint().abs_diff(I16F16::lit("0.05")) < 0.001);
+
+        // Position mode holds the position with the same velocity
+        motion.set_mode(ControlMode::Position);
+        assert!(motion.setpoint().abs_diff(I16F16::from_num(state.1)) < 0.01);
+        let velocity = motion.velocity_setpoint();
+        run(&mut motion, &mut state, 1, dt);
+        assert!(motion.velocity_setpoint().abs_diff(velocity) < 0.05);
+        assert!(motion.current_setpoint().abs_diff(I16F16::lit("0.05")) < 0.005);
+
+        // Torque mode holds the current
+        motion.set_mode(ControlMode::Torque);
+        let current = motion.current_setpoint();
+        assert_eq!(motion.setpoint(), current);
+        run(&mut motion, &mut state, 1, dt);
+        assert_eq!(motion.current_setpoint(), current);
+    }
+
+    #[test]
+    fn current_is_limited_by_foc() {
+        let position = pid::PIDController::new(I16F16::ZERO, I16F16::ZERO, I16F16::ZERO);
+        let mut motion = controller(velocity_controller(), position);
+        motion.foc_mut().set_current_limit(I16F16::from_num(5));
+
+        motion.set_setpoint(I16F16::from_num(8));
+        let mut state = (0., 0.);
+        run(&mut motion, &mut state, 1, 1. / 1024.);
+        assert_eq!(motion.current_setpoint(), 5);
+
+        // The velocity loop gets the headroom left by the d-axis setpoint
+        motion
+            .foc_mut()
+            .set_d_current_setpoint(I16F16::from_num(-3));
+        motion.set_mode(ControlMode::Velocity);
+        motion.set_setpoint(I16F16::from_num(-100));
+        run(&mut motion, &mut state, 16, 1. / 1024.);
+        assert!(motion.current_setpoint().abs_diff(I16F16::from_num(-4)) < 0.001);
+    }
+}
@@ -0,0 +1,171 @@
+//! Electrical and mechanical parameters of a permanent magnet synchronous
+//! motor.
+
+use fixed::types::{I16F16, I32F32};
+
+use crate::{park_clarke::RotatingReferenceFrame, KILO};
+
+/// The parameters of a motor.
+///
+/// The inductances, flux linkage, inertia and friction are given in
+/// milli-units so that typical values can be represented with reasonable
+/// precision in [`I16F16`].
+///
+/// Prefer [`MotorParameters::new`] to a struct literal, so that parameters
+/// added later get a default.
+#[derive(Debug, Clone)]
+pub struct MotorParameters {
+    /// Phase resistance, in ohms.
+    pub resistance: I16F16,
+    /// d-axis inductance, in millihenries.
+    pub d_inductance: I16F16,
+    /// q-axis inductance, in millihenries.
+    pub q_inductance: I16F16,
+    /// Permanent magnet flux linkage, in milliwebers.
+    pub flux_linkage: I16F16,
+    /// Number of pole pairs.
+    pub pole_pairs: u8,
+    /// Rotor inertia, in g·m².
+    pub inertia: I16F16,
+    /// Viscous friction, in mN·m·s/rad.
+    pub viscous_friction: I16F16,
+}
+
+impl MotorParameters {
+    /// Create new motor parameters, with zero inertia and viscous friction.
+    ///
+    /// Params:
+    /// - `resistance`: phase resistance in ohms
+    /// - `d_inductance`: d-axis inductance in millihenries
+    /// - `q_inductance`: q-axis inductance in millihenries
+    /// - `flux_linkage`: permanent magnet flux linkage in milliwebers
+    /// - `pole_pairs`: number of pole pairs
+    pub fn new(
+        resistance: I16F16,
+        d_inductance: I16F16,
+        q_inductance: I16F16,
+        flux_linkage: I16F16,
+        pole_pairs: u8,
+    ) -> Self {
+        Self {
+            resistance,
+            d_inductance,
+            q_inductance,
+            flux_linkage,
+            pole_pairs,
+            inertia: I16F16::ZERO,
+            viscous_friction: I16F16::ZERO,
+        }
+    }
+
+    /// Set the rotor inertia, in g·m².
+    pub fn with_inertia(mut self, inertia: I16F16) -> Self {
+        self.inertia = inertia;
+        self
+    }
+
+    /// Set the viscous friction, in mN·m·s/rad.
+    pub fn with_viscous_friction(mut self, viscous_friction: I16F16) -> Self {
+        self.viscous_friction = viscous_friction;
+        self
+    }
+
+    /// The back-EMF voltage at the given electrical velocity (in rad/s), in
+    /// volts.
+    pub fn back_emf(&self, electrical_velocity: I16F16) -> I16F16 {
+        per_kilo(electrical_velocity, self.flux_linkage)
+    }
+
+    /// The torque per amp of q-axis current, in N·m/A.
+    pub fn torque_constant(&self) -> I16F16 {
+        (self.flux_linkage / KILO)
+            .saturating_mul_int(i32::from(self.pole_pairs))
+            .saturating_mul(I16F16::lit("1.5"))
+    }
+
+    /// The velocity constant, in rpm per volt of peak line-to-line back-EMF.
+    ///
+    /// Returns zero if the flux linkage or pole pairs are not positive.
+    pub fn kv(&self) -> I16F16 {
+        // The peak line-to-line back-EMF is √3·p·ψ per mechanical rad/s
+        let volts_per_radian = (self.flux_linkage / KILO)
+            .saturating_mul_int(i32::from(self.pole_pairs))
+            .saturating_mul(crate::SQRT_3);
+        if volts_per_radian <= I16F16::ZERO {
+            return I16F16::ZERO;
+        }
+        (I16F16::from_num(60) / I16F16::TAU).saturating_div(volts_per_radian)
+    }
+
+    /// The feed-forward voltages which cancel the cross-coupling between the
+    /// d and q axes and the back-EMF, in volts.
+    ///
+    /// Params:
+    /// - `electrical_velocity`: rad/s
+    /// - `current`: the measured d/q current in amps
+    pub fn decoupling_voltage(
+        &self,
+        electrical_velocity: I16F16,
+        current: &RotatingReferenceFrame,
+    ) -> RotatingReferenceFrame {
+        let d_flux = self
+            .d_inductance
+            .saturating_mul(current.d)
+            .saturating_add(self.flux_linkage);
+        let q_flux = self.q_inductance.saturating_mul(current.q);
+
+        RotatingReferenceFrame {
+            d: per_kilo(electrical_velocity, q_flux).saturating_neg(),
+            q: per_kilo(electrical_velocity, d_flux),
+        }
+    }
+}
+
+/// The product of a velocity and a milli-unit, divided by 1000.
+///
+/// The product is formed before dividing so that low velocities are not
+/// rounded to zero.
+fn per_kilo(velocity: I16F16, milli: I16F16) -> I16F16 {
+    (I32F32::from(velocity).saturating_mul(I32F32::from(milli)) / I32F32::from(KILO))
+        .saturating_to_num()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn feed_forward_at_low_velocity() {
+        let motor = MotorParameters::new(
+            I16F16::lit("0.1"),
+            I16F16::lit("0.2"),
+            I16F16::lit("0.3"),
+            I16F16::lit("5"),
+            7,
+        );
+        let velocity = I16F16::lit("0.7");
+        let current = RotatingReferenceFrame {
+            d: I16F16::lit("-2"),
+            q: I16F16::lit("3"),
+        };
+
+        let back_emf = motor.back_emf(velocity);
+        assert!(
+            back_emf.abs_diff(I16F16::lit("0.0035")) < 0.00003,
+            "{back_emf}"
+        );
+
+        // d: -0.7 * 0.3 * 3 / 1000, q: 0.7 * (0.2 * -2 + 5) / 1000
+        let voltage = motor.decoupling_voltage(velocity, &current);
+        assert!(
+            voltage.d.abs_diff(I16F16::lit("-0.00063")) < 0.00003,
+            "{}",
+            voltage.d
+        );
+        assert!(
+            voltage.q.abs_diff(I16F16::lit("0.00322")) < 0.00003,
+            "{}",
+            voltage.q
+        );
+    }
+}
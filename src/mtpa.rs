@@ -0,0 +1,363 @@
+//! Maximum torque per ampere (MTPA) current references.
+//!
+//! The torque of a motor is `T = 1.5·p·iq·(ψ + (Ld - Lq)·id)`. For a salient
+//! (interior permanent magnet) motor with `Lq > Ld`, negative d-axis current
+//! adds reluctance torque, so a torque can be produced with less current than
+//! with `id = 0`. A [`TorqueReference`] turns a torque in N·m into the d/q
+//! current with the smallest magnitude that produces it.
+//!
+//! [`Mtpa`] calculates the currents directly, while [`MtpaTable`]
+//! interpolates a table which is calculated in advance. Within the range of
+//! the table this needs no division, which is cheaper on targets without
+//! hardware division. Either can be used with
+//! [`Foc::update_torque`](crate::Foc::update_torque).
+
+use fixed::types::I16F16;
+
+use crate::{motor::MotorParameters, park_clarke::RotatingReferenceFrame, KILO};
+
+/// Number of fixed-point iterations used to find the currents for a torque.
+const ITERATIONS: usize = 8;
+
+/// Generates d/q current references from a torque.
+pub trait TorqueReference {
+    /// The d/q current in amps which produces the given torque in N·m.
+    fn currents(&self, torque: I16F16) -> RotatingReferenceFrame;
+
+    /// The q-axis current in amps which produces the given torque in N·m
+    /// with the given d-axis current in amps.
+    fn q_current(&self, torque: I16F16, d_current: I16F16) -> I16F16;
+
+    /// The d/q current in amps which produces the given torque in N·m, where
+    /// the d-axis current is no more than `d_current`, e.g. from
+    /// [`FieldWeakening`](crate::field_weakening::FieldWeakening).
+    ///
+    /// Below the base speed this is the same as
+    /// [`TorqueReference::currents`]. Once field weakening requires more
+    /// negative d-axis current, the q-axis current is recalculated to keep
+    /// the torque.
+    fn weakened_currents(&self, torque: I16F16, d_current: I16F16) -> RotatingReferenceFrame {
+        let currents = self.currents(torque);
+        if d_current < currents.d {
+            RotatingReferenceFrame {
+                d: d_current,
+                q: self.q_current(torque, d_current),
+            }
+        } else {
+            currents
+        }
+    }
+}
+
+/// Calculates the MTPA currents from the motor parameters.
+#[derive(Debug, Clone)]
+pub struct Mtpa {
+    /// `1.5·p`.
+    torque_factor: I16F16,
+    /// Flux linkage in milliwebers.
+    flux_linkage: I16F16,
+    /// `Lq - Ld` in millihenries.
+    saliency: I16F16,
+}
+
+impl Mtpa {
+    /// Create a new MTPA calculation for the given motor.
+    pub fn new(motor: &MotorParameters) -> Self {
+        Self {
+            torque_factor: I16F16::lit("1.5").saturating_mul_int(i32::from(motor.pole_pairs)),
+            flux_linkage: motor.flux_linkage,
+            saliency: motor.q_inductance.saturating_sub(motor.d_inductance),
+        }
+    }
+
+    /// The torque in N·m produced by the given d/q current in amps.
+    pub fn torque(&self, current: &RotatingReferenceFrame) -> I16F16 {
+        let flux = self
+            .flux_linkage
+            .saturating_sub(self.saliency.saturating_mul(current.d));
+        (self.torque_factor.saturating_mul(current.q) / KILO).saturating_mul(flux)
+    }
+
+    /// The d-axis current in amps on the MTPA trajectory for the given
+    /// q-axis current in amps.
+    ///
+    /// This is `id = a - sign(a)·√(a² + iq²)` where `a = ψ / 2(Lq - Ld)`,
+    /// calculated in a form which stays accurate for a motor with little
+    /// saliency.
+    pub fn d_current(&self, q_current: I16F16) -> I16F16 {
+        if self.saliency == I16F16::ZERO || q_current == I16F16::ZERO {
+            return I16F16::ZERO;
+        }
+        let a = (self.flux_linkage / 2).saturating_div(self.saliency);
+        let square = q_current.saturating_mul(q_current);
+        let denominator = a
+            .saturating_abs()
+            .saturating_add(crate::hypot(a, q_current));
+        let magnitude = square.saturating_div(denominator);
+        if a.is_negative() {
+            magnitude
+        } else {
+            -magnitude
+        }
+    }
+}
+
+impl TorqueReference for Mtpa {
+    /// The torque equation is solved for the MTPA trajectory with a few
+    /// damped fixed-point iterations, starting from `id = 0`. The q-axis
+    /// current is then calculated from the final d-axis current, so the
+    /// torque is exact even if the iterations have not fully converged.
+    fn currents(&self, torque: I16F16) -> RotatingReferenceFrame {
+        let mut d = I16F16::ZERO;
+        for _ in 0..ITERATIONS {
+            let q = self.q_current(torque, d);
+            // Without damping the iterations oscillate for salient motors
+            d = (d + self.d_current(q)) / 2;
+        }
+        RotatingReferenceFrame {
+            d,
+            q: self.q_current(torque, d),
+        }
+    }
+
+    fn q_current(&self, torque: I16F16, d_current: I16F16) -> I16F16 {
+        let flux = self
+            .flux_linkage
+            .saturating_sub(self.saliency.saturating_mul(d_current));
+        if flux <= I16F16::ZERO || self.torque_factor == I16F16::ZERO {
+            return I16F16::ZERO;
+        }
+        torque
+            .saturating_mul(KILO)
+            .saturating_div(self.torque_factor)
+            .saturating_div(flux)
+    }
+}
+
+/// A table of MTPA currents at `N` evenly spaced torques, from zero to a
+/// maximum torque.
+///
+/// The currents are linearly interpolated between the points, and negative
+/// torques use the same d-axis current as the positive torque. Above the
+/// maximum torque the d-axis current of the last point is used, and the
+/// q-axis current is calculated with [`Mtpa`].
+#[derive(Debug, Clone)]
+pub struct MtpaTable<const N: usize> {
+    mtpa: Mtpa,
+    max_torque: I16F16,
+    inverse_step: I16F16,
+    points: [RotatingReferenceFrame; N],
+}
+
+impl<const N: usize> MtpaTable<N> {
+    /// Calculate the table with [`Mtpa`] up to the given torque in N·m.
+    pub fn new(mtpa: Mtpa, max_torque: I16F16) -> Self {
+        let step = torque_step::<N>(max_torque);
+        let points = core::array::from_fn(|i| mtpa.currents(step.saturating_mul_int(i as i32)));
+        Self {
+            mtpa,
+            max_torque,
+            inverse_step: inverse_torque_step::<N>(max_torque),
+            points,
+        }
+    }
+
+    /// Create a table from currents calculated in advance, e.g. offline from
+    /// measured torque data, at `N` evenly spaced torques from zero to the
+    /// given torque in N·m.
+    ///
+    /// `mtpa` is used to calculate the q-axis current above the maximum
+    /// torque and with field weakening.
+    pub fn from_points(
+        mtpa: Mtpa,
+        max_torque: I16F16,
+        points: [RotatingReferenceFrame; N],
+    ) -> Self {
+        Self {
+            mtpa,
+            max_torque,
+            inverse_step: inverse_torque_step::<N>(max_torque),
+            points,
+        }
+    }
+
+    /// The currents at each point of the table.
+    pub fn points(&self) -> &[RotatingReferenceFrame; N] {
+        &self.points
+    }
+}
+
+impl<const N: usize> TorqueReference for MtpaTable<N> {
+    fn currents(&self, torque: I16F16) -> RotatingReferenceFrame {
+        let magnitude = torque.saturating_abs();
+        let Some(last) = self.points.last() else {
+            return self.mtpa.currents(torque);
+        };
+
+        let current = if N < 2 || magnitude >= self.max_torque {
+            RotatingReferenceFrame {
+                d: last.d,
+                q: self.mtpa.q_current(magnitude, last.d),
+            }
+        } else {
+            let position = magnitude.saturating_mul(self.inverse_step);
+            let index = (position.to_num::<usize>()).min(N - 2);
+            let fraction = position - I16F16::from_num(index);
+            let (low, high) = (&self.points[index], &self.points[index + 1]);
+            RotatingReferenceFrame {
+                d: low.d + (high.d - low.d).saturating_mul(fraction),
+                q: low.q + (high.q - low.q).saturating_mul(fraction),
+            }
+        };
+
+        if torque.is_negative() {
+            RotatingReferenceFrame {
+                d: current.d,
+                q: -current.q,
+            }
+        } else {
+            current
+        }
+    }
+
+    fn q_current(&self, torque: I16F16, d_current: I16F16) -> I16F16 {
+        self.mtpa.q_current(torque, d_current)
+    }
+}
+
+/// The torque between the points of a table.
+fn torque_step<const N: usize>(max_torque: I16F16) -> I16F16 {
+    max_torque / (N.max(2) as i32 - 1)
+}
+
+/// The number of points of a table per N·m, or zero if the maximum torque is
+/// not positive.
+fn inverse_torque_step<const N: usize>(max_torque: I16F16) -> I16F16 {
+    if max_torque <= I16F16::ZERO {
+        return I16F16::ZERO;
+    }
+    I16F16::saturating_from_num(N.max(2) - 1).saturating_div(max_torque)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{pid::PIController, pwm, Foc};
+
+    fn motor() -> MotorParameters {
+        MotorParameters::new(
+            I16F16::lit("0.1"),
+            I16F16::lit("0.1"),
+            I16F16::lit("0.3"),
+            I16F16::from_num(5),
+            4,
+        )
+    }
+
+    #[test]
+    fn minimises_current() {
+        let mtpa = Mtpa::new(&motor());
+        for torque in [0.05f32, 0.2, 0.5, -0.5] {
+            let torque = I16F16::from_num(torque);
+            let currents = mtpa.currents(torque);
+            assert!(currents.d <= 0);
+            assert!(
+                mtpa.torque(&currents).abs_diff(torque) < 0.002,
+                "{currents:?}"
+            );
+
+            // Moving along the constant torque curve needs more current
+            let magnitude = crate::limiter::magnitude(&currents);
+            for offset in [-1, 1] {
+                let d = currents.d + I16F16::from_num(offset);
+                let other = RotatingReferenceFrame {
+                    d,
+                    q: mtpa.q_current(torque, d),
+                };
+                assert!(crate::limiter::magnitude(&other) > magnitude);
+            }
+        }
+
+        // A non-salient motor uses no d-axis current
+        let mut surface = motor();
+        surface.q_inductance = surface.d_inductance;
+        let currents = Mtpa::new(&surface).currents(I16F16::lit("0.5"));
+        assert_eq!(currents.d, 0);
+        assert!(currents.q.abs_diff(I16F16::lit("16.667")) < 0.01);
+    }
+
+    #[test]
+    fn table_matches_calculation() {
+        let mtpa = Mtpa::new(&motor());
+        let table = MtpaTable::<17>::new(mtpa.clone(), I16F16::ONE);
+        for step in -50..=50 {
+            let torque = I16F16::from_num(step as f32 * 0.02);
+            let expected = mtpa.currents(torque);
+            let currents = table.currents(torque);
+            assert!(currents.d.abs_diff(expected.d) < 0.1, "{torque}");
+            assert!(currents.q.abs_diff(expected.q) < 0.1, "{torque}");
+        }
+
+        // Above the table the torque is still produced
+        let torque = I16F16::lit("1.5");
+        let currents = table.currents(torque);
+        assert_eq!(currents.d, table.points()[16].d);
+        assert!(mtpa.torque(&currents).abs_diff(torque) < 0.002);
+    }
+
+    #[test]
+    fn merges_with_field_weakening() {
+        let mtpa = Mtpa::new(&motor());
+        let torque = I16F16::lit("0.3");
+        let currents = mtpa.currents(torque);
+
+        // Less negative d-axis current than MTPA is ignored
+        let weakened = mtpa.weakened_currents(torque, I16F16::ZERO);
+        assert_eq!(weakened.d, currents.d);
+
+        // More negative d-axis current keeps the torque
+        let weakened = mtpa.weakened_currents(torque, I16F16::from_num(-10));
+        assert_eq!(weakened.d, -10);
+        assert!(mtpa.torque(&weakened).abs_diff(torque) < 0.002);
+        assert!(weakened.q < currents.q);
+    }
+
+    #[test]
+    fn controls_torque() {
+        // A proportional gain of one outputs the current error as a voltage
+        let controller = || PIController::new(-I16F16::ONE, I16F16::ZERO);
+        let mut foc: Foc<pwm::SpaceVector, 1000> =
+            Foc::new(controller(), controller(), I16F16::from_num(24));
+        let mtpa = Mtpa::new(&motor());
+        let torque = I16F16::lit("0.3");
+        let dt = I16F16::lit("0.001");
+
+        foc.update_torque(
+            [I16F16::ZERO; 2],
+            I16F16::ZERO,
+            I16F16::ZERO,
+            torque,
+            &mtpa,
+            dt,
+        );
+        let voltage = foc.orthogonal_voltage();
+        let expected = mtpa.currents(torque);
+        assert!(voltage.alpha.abs_diff(expected.d) < 0.01);
+        assert!(voltage.beta.abs_diff(expected.q) < 0.01);
+
+        // Field weakening takes over the d-axis
+        foc.set_d_current_setpoint(I16F16::from_num(-10));
+        foc.update_torque(
+            [I16F16::ZERO; 2],
+            I16F16::ZERO,
+            I16F16::ZERO,
+            torque,
+            &mtpa,
+            dt,
+        );
+        let voltage = foc.orthogonal_voltage();
+        let expected = mtpa.weakened_currents(torque, I16F16::from_num(-10));
+        assert!(voltage.alpha.abs_diff(expected.d) < 0.01);
+        assert!(voltage.beta.abs_diff(expected.q) < 0.01);
+    }
+}
@@ -0,0 +1,25 @@
+//! Sensorless observers, which estimate the rotor angle and velocity from the
+//! stationary frame voltages and currents.
+//!
+//! The voltage and current are available from [`Foc::orthogonal_voltage`] and
+//! [`Foc::orthogonal_current`] after each update, and the estimated angle can
+//! be used directly as the `angle` argument of [`Foc::update`].
+//!
+//! [`Foc::orthogonal_voltage`]: crate::Foc::orthogonal_voltage
+//! [`Foc::orthogonal_current`]: crate::Foc::orthogonal_current
+//! [`Foc::update`]: crate::Foc::update
+
+mod flux;
+mod sliding_mode;
+
+pub use flux::FluxObserver;
+pub use sliding_mode::SlidingModeObserver;
+
+/// Errors from creating an observer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObserverError {
+    /// The inductance of the motor is not positive.
+    InvalidInductance,
+    /// The flux linkage of the motor is not positive.
+    InvalidFluxLinkage,
+}
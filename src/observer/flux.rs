@@ -0,0 +1,233 @@
+use fixed::types::I16F16;
+
+use crate::{
+    angle, motor::MotorParameters, observer::ObserverError, park_clarke::TwoPhaseReferenceFrame,
+    KILO,
+};
+
+/// A non-linear flux observer, based on [Ortega et al.](https://doi.org/10.1109/TCST.2010.2047396)
+///
+/// The stator flux is estimated by integrating `v - R·i`, and the rotor flux
+/// is the stator flux minus `L·i`. Integration drift is removed by pulling
+/// the rotor flux estimate onto a circle with a radius of the flux linkage.
+/// The rotor angle is the angle of the rotor flux vector.
+///
+/// This needs very little tuning, and works down to lower speeds than
+/// observers based on the back-EMF.
+pub struct FluxObserver {
+    resistance: I16F16,
+    inductance: I16F16,
+    flux_linkage: I16F16,
+    gain: I16F16,
+    filter_bandwidth: I16F16,
+    stator_flux: TwoPhaseReferenceFrame,
+    rotor_flux: TwoPhaseReferenceFrame,
+    last_angle: Option<I16F16>,
+    angle: I16F16,
+    velocity: I16F16,
+}
+
+impl FluxObserver {
+    /// Create a new flux observer for the given motor.
+    ///
+    /// Params:
+    /// - `gain`: observer gain in 1/s, which sets how quickly the flux estimate
+    ///   is pulled onto the flux linkage circle. This is `γψ²/2` in terms of
+    ///   the original paper. A gain around the minimum operating electrical
+    ///   speed is a good starting point, as too high a gain distorts the
+    ///   estimate. For the discrete observer to be stable, `gain * dt` must be
+    ///   much less than 1.
+    /// - `filter_bandwidth`: bandwidth of the velocity filter in rad/s
+    ///
+    /// Returns [`ObserverError::InvalidFluxLinkage`] unless the flux linkage
+    /// is positive, as the flux estimate is normalised by it.
+    pub fn new(
+        motor: &MotorParameters,
+        gain: I16F16,
+        filter_bandwidth: I16F16,
+    ) -> Result<Self, ObserverError> {
+        if motor.flux_linkage <= I16F16::ZERO {
+            return Err(ObserverError::InvalidFluxLinkage);
+        }
+
+        Ok(Self {
+            resistance: motor.resistance,
+            inductance: motor.q_inductance,
+            flux_linkage: motor.flux_linkage,
+            gain,
+            filter_bandwidth,
+            stator_flux: TwoPhaseReferenceFrame {
+                alpha: I16F16::ZERO,
+                beta: I16F16::ZERO,
+            },
+            rotor_flux: TwoPhaseReferenceFrame {
+                alpha: I16F16::ZERO,
+                beta: I16F16::ZERO,
+            },
+            last_angle: None,
+            angle: I16F16::ZERO,
+            velocity: I16F16::ZERO,
+        })
+    }
+
+    /// Update the observer.
+    ///
+    /// Params:
+    /// - `voltage`: applied stationary frame voltage in volts
+    /// - `current`: measured stationary frame current in amps
+    /// - `dt`: time delta since last update in seconds
+    pub fn update(
+        &mut self,
+        voltage: &TwoPhaseReferenceFrame,
+        current: &TwoPhaseReferenceFrame,
+        dt: I16F16,
+    ) {
+        // Error between the estimated flux magnitude and the flux linkage,
+        // normalised to the flux linkage
+        let normalised_alpha = self.rotor_flux.alpha.saturating_div(self.flux_linkage);
+        let normalised_beta = self.rotor_flux.beta.saturating_div(self.flux_linkage);
+        let error = I16F16::ONE
+            - normalised_alpha
+                .saturating_mul(normalised_alpha)
+                .saturating_add(normalised_beta.saturating_mul(normalised_beta));
+        let correction = self.gain.saturating_mul(error);
+
+        // Flux is in milliwebers
+        let dt_milli = dt.saturating_mul(KILO);
+        let correction_dt = correction.saturating_mul(dt);
+        self.stator_flux.alpha = self
+            .stator_flux
+            .alpha
+            .saturating_add(
+                voltage
+                    .alpha
+                    .saturating_sub(self.resistance.saturating_mul(current.alpha))
+                    .saturating_mul(dt_milli),
+            )
+            .saturating_add(correction_dt.saturating_mul(self.rotor_flux.alpha));
+        self.stator_flux.beta = self
+            .stator_flux
+            .beta
+            .saturating_add(
+                voltage
+                    .beta
+                    .saturating_sub(self.resistance.saturating_mul(current.beta))
+                    .saturating_mul(dt_milli),
+            )
+            .saturating_add(correction_dt.saturating_mul(self.rotor_flux.beta));
+
+        self.rotor_flux = TwoPhaseReferenceFrame {
+            alpha: self
+                .stator_flux
+                .alpha
+                .saturating_sub(self.inductance.saturating_mul(current.alpha)),
+            beta: self
+                .stator_flux
+                .beta
+                .saturating_sub(self.inductance.saturating_mul(current.beta)),
+        };
+
+        let angle = angle::wrap(angle::atan2(self.rotor_flux.beta, self.rotor_flux.alpha));
+        if let Some(last_angle) = self.last_angle {
+            if dt > I16F16::ZERO {
+                let raw_velocity = angle::difference(angle, last_angle).saturating_div(dt);
+                self.velocity =
+                    crate::low_pass(self.velocity, raw_velocity, self.filter_bandwidth, dt);
+            }
+        }
+        self.last_angle = Some(angle);
+        self.angle = angle;
+    }
+
+    /// The estimated electrical angle in radians, in `[0, 2π)`.
+    pub fn angle(&self) -> I16F16 {
+        self.angle
+    }
+
+    /// The estimated electrical velocity in rad/s.
+    pub fn velocity(&self) -> I16F16 {
+        self.velocity
+    }
+
+    /// The magnitude of the estimated rotor flux in milliwebers.
+    ///
+    /// Once converged this is close to the flux linkage of the motor.
+    pub fn flux_magnitude(&self) -> I16F16 {
+        crate::hypot(self.rotor_flux.alpha, self.rotor_flux.beta)
+    }
+
+    /// The estimated stationary frame rotor flux in milliwebers.
+    pub fn rotor_flux(&self) -> &TwoPhaseReferenceFrame {
+        &self.rotor_flux
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tracks_spinning_motor() {
+        let (resistance, inductance, flux_linkage) = (0.5f32, 1e-3f32, 0.01f32);
+        let motor = MotorParameters::new(
+            I16F16::from_num(resistance),
+            I16F16::from_num(inductance * 1e3),
+            I16F16::from_num(inductance * 1e3),
+            I16F16::from_num(flux_linkage * 1e3),
+            7,
+        );
+        let mut observer =
+            FluxObserver::new(&motor, I16F16::from_num(500), I16F16::from_num(1000)).unwrap();
+
+        let dt = 1. / 16384.;
+        let velocity = 300f32;
+        let mut angle = 1f32;
+        let mut current = [0f32; 2];
+
+        for step in 0..8000 {
+            // Apply a voltage leading the back-EMF
+            let voltage_angle = angle + core::f32::consts::FRAC_PI_2 + 0.2;
+            let voltage = [4. * voltage_angle.cos(), 4. * voltage_angle.sin()];
+            let emf = [
+                -velocity * flux_linkage * angle.sin(),
+                velocity * flux_linkage * angle.cos(),
+            ];
+
+            observer.update(
+                &TwoPhaseReferenceFrame {
+                    alpha: I16F16::from_num(voltage[0]),
+                    beta: I16F16::from_num(voltage[1]),
+                },
+                &TwoPhaseReferenceFrame {
+                    alpha: I16F16::from_num(current[0]),
+                    beta: I16F16::from_num(current[1]),
+                },
+                I16F16::from_num(dt),
+            );
+
+            for i in 0..2 {
+                current[i] += dt / inductance * (voltage[i] - resistance * current[i] - emf[i]);
+            }
+            angle = (angle + velocity * dt) % core::f32::consts::TAU;
+
+            if step > 6000 {
+                let error = angle::difference(observer.angle(), I16F16::from_num(angle));
+                assert!(error.abs() < 0.05, "angle error {error}");
+                assert!(observer.velocity().abs_diff(I16F16::from_num(velocity)) < 5);
+                assert!(observer.flux_magnitude().abs_diff(motor.flux_linkage) < 0.2);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_flux_linkage() {
+        let mut motor =
+            MotorParameters::new(I16F16::ONE, I16F16::ONE, I16F16::ONE, I16F16::ZERO, 7);
+        let observer = FluxObserver::new(&motor, I16F16::ONE, I16F16::ONE);
+        assert_eq!(observer.err(), Some(ObserverError::InvalidFluxLinkage));
+
+        motor.flux_linkage = I16F16::from_num(-5);
+        let observer = FluxObserver::new(&motor, I16F16::ONE, I16F16::ONE);
+        assert_eq!(observer.err(), Some(ObserverError::InvalidFluxLinkage));
+    }
+}
@@ -0,0 +1,266 @@
+use fixed::types::{I16F16, I32F32};
+
+use crate::{
+    angle, motor::MotorParameters, observer::ObserverError, park_clarke::TwoPhaseReferenceFrame,
+    KILO,
+};
+
+/// A sliding-mode observer, which estimates the back-EMF of the motor to
+/// extract the rotor angle.
+///
+/// A model of the stator current is driven by the applied voltage and a
+/// switching term, which forces the estimated current to track the measured
+/// current. The switching term then contains the back-EMF, which is extracted
+/// with a low-pass filter. The phase lag of the filter at the estimated speed
+/// is compensated for.
+///
+/// As the back-EMF is proportional to speed, the estimate is only valid above
+/// a minimum speed.
+pub struct SlidingModeObserver {
+    resistance: I16F16,
+    inverse_inductance: I32F32,
+    gain: I16F16,
+    boundary_layer: I16F16,
+    filter_bandwidth: I16F16,
+    current: TwoPhaseReferenceFrame,
+    back_emf: TwoPhaseReferenceFrame,
+    last_raw_angle: Option<I16F16>,
+    angle: I16F16,
+    velocity: I16F16,
+}
+
+impl SlidingModeObserver {
+    /// Create a new sliding-mode observer for the given motor.
+    ///
+    /// Params:
+    /// - `gain`: amplitude of the switching term in volts, which must be
+    ///   larger than the largest expected back-EMF
+    /// - `boundary_layer`: current error in amps over which the switching term
+    ///   is linear rather than a sign function, to reduce chattering. For the
+    ///   discrete observer to be stable, `gain / boundary_layer * dt / L`
+    ///   should be less than 1.
+    /// - `filter_bandwidth`: bandwidth of the back-EMF and velocity filters in
+    ///   rad/s
+    ///
+    /// Returns [`ObserverError::InvalidInductance`] unless the q-axis
+    /// inductance is positive.
+    pub fn new(
+        motor: &MotorParameters,
+        gain: I16F16,
+        boundary_layer: I16F16,
+        filter_bandwidth: I16F16,
+    ) -> Result<Self, ObserverError> {
+        if motor.q_inductance <= I16F16::ZERO {
+            return Err(ObserverError::InvalidInductance);
+        }
+
+        Ok(Self {
+            resistance: motor.resistance,
+            // In 1/H, which can be larger than the range of `I16F16` for
+            // small inductances
+            inverse_inductance: I32F32::from(KILO) / I32F32::from(motor.q_inductance),
+            gain,
+            boundary_layer,
+            filter_bandwidth,
+            current: TwoPhaseReferenceFrame {
+                alpha: I16F16::ZERO,
+                beta: I16F16::ZERO,
+            },
+            back_emf: TwoPhaseReferenceFrame {
+                alpha: I16F16::ZERO,
+                beta: I16F16::ZERO,
+            },
+            last_raw_angle: None,
+            angle: I16F16::ZERO,
+            velocity: I16F16::ZERO,
+        })
+    }
+
+    /// Update the observer.
+    ///
+    /// Params:
+    /// - `voltage`: applied stationary frame voltage in volts
+    /// - `current`: measured stationary frame current in amps
+    /// - `dt`: time delta since last update in seconds
+    pub fn update(
+        &mut self,
+        voltage: &TwoPhaseReferenceFrame,
+        current: &TwoPhaseReferenceFrame,
+        dt: I16F16,
+    ) {
+        let dt_over_inductance = I32F32::from(dt)
+            .saturating_mul(self.inverse_inductance)
+            .saturating_to_num::<I16F16>();
+
+        let switching = TwoPhaseReferenceFrame {
+            alpha: self.switching(self.current.alpha.saturating_sub(current.alpha)),
+            beta: self.switching(self.current.beta.saturating_sub(current.beta)),
+        };
+
+        // Current model: L di/dt = v - R i - z
+        self.current.alpha = self.current.alpha.saturating_add(
+            dt_over_inductance.saturating_mul(
+                voltage
+                    .alpha
+                    .saturating_sub(self.resistance.saturating_mul(self.current.alpha))
+                    .saturating_sub(switching.alpha),
+            ),
+        );
+        self.current.beta = self.current.beta.saturating_add(
+            dt_over_inductance.saturating_mul(
+                voltage
+                    .beta
+                    .saturating_sub(self.resistance.saturating_mul(self.current.beta))
+                    .saturating_sub(switching.beta),
+            ),
+        );
+
+        self.back_emf.alpha = crate::low_pass(
+            self.back_emf.alpha,
+            switching.alpha,
+            self.filter_bandwidth,
+            dt,
+        );
+        self.back_emf.beta = crate::low_pass(
+            self.back_emf.beta,
+            switching.beta,
+            self.filter_bandwidth,
+            dt,
+        );
+
+        // The back-EMF leads the rotor flux by 90 degrees
+        let raw_angle = angle::atan2(-self.back_emf.alpha, self.back_emf.beta);
+
+        if let Some(last_raw_angle) = self.last_raw_angle {
+            if dt > I16F16::ZERO {
+                let raw_velocity = angle::difference(raw_angle, last_raw_angle).saturating_div(dt);
+                self.velocity =
+                    crate::low_pass(self.velocity, raw_velocity, self.filter_bandwidth, dt);
+            }
+        }
+        self.last_raw_angle = Some(raw_angle);
+
+        // The back-EMF is reversed when rotating backwards, and the filter
+        // lags by atan(ω/ωc)
+        let direction_correction = if self.velocity.is_negative() {
+            I16F16::PI
+        } else {
+            I16F16::ZERO
+        };
+        let lag_correction = angle::atan2(self.velocity, self.filter_bandwidth);
+        self.angle = angle::wrap(raw_angle + direction_correction + lag_correction);
+    }
+
+    fn switching(&self, error: I16F16) -> I16F16 {
+        let normalised = if self.boundary_layer > I16F16::ZERO {
+            error.saturating_div(self.boundary_layer)
+        } else {
+            error.signum()
+        };
+        self.gain
+            .saturating_mul(normalised.clamp(-I16F16::ONE, I16F16::ONE))
+    }
+
+    /// The estimated electrical angle in radians, in `[0, 2π)`.
+    pub fn angle(&self) -> I16F16 {
+        self.angle
+    }
+
+    /// The estimated electrical velocity in rad/s.
+    pub fn velocity(&self) -> I16F16 {
+        self.velocity
+    }
+
+    /// The estimated stationary frame back-EMF in volts.
+    pub fn back_emf(&self) -> &TwoPhaseReferenceFrame {
+        &self.back_emf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tracks_spinning_motor() {
+        let (resistance, inductance, flux_linkage) = (0.5f32, 1e-3f32, 0.01f32);
+        let motor = MotorParameters::new(
+            I16F16::from_num(resistance),
+            I16F16::from_num(inductance * 1e3),
+            I16F16::from_num(inductance * 1e3),
+            I16F16::from_num(flux_linkage * 1e3),
+            7,
+        );
+        let mut observer = SlidingModeObserver::new(
+            &motor,
+            I16F16::from_num(20),
+            I16F16::from_num(2),
+            I16F16::from_num(2000),
+        )
+        .unwrap();
+
+        let dt = 1. / 16384.;
+        let velocity = 1000f32;
+        let mut angle = 0f32;
+        let mut current = [0f32; 2];
+
+        for step in 0..8000 {
+            // Apply a voltage leading the back-EMF
+            let voltage_angle = angle + core::f32::consts::FRAC_PI_2 + 0.2;
+            let voltage = [12. * voltage_angle.cos(), 12. * voltage_angle.sin()];
+            let emf = [
+                -velocity * flux_linkage * angle.sin(),
+                velocity * flux_linkage * angle.cos(),
+            ];
+
+            observer.update(
+                &TwoPhaseReferenceFrame {
+                    alpha: I16F16::from_num(voltage[0]),
+                    beta: I16F16::from_num(voltage[1]),
+                },
+                &TwoPhaseReferenceFrame {
+                    alpha: I16F16::from_num(current[0]),
+                    beta: I16F16::from_num(current[1]),
+                },
+                I16F16::from_num(dt),
+            );
+
+            if step > 6000 {
+                let error = angle::difference(observer.angle(), I16F16::from_num(angle));
+                assert!(error.abs() < 0.1, "angle error {error}");
+                assert!(observer.velocity().abs_diff(I16F16::from_num(velocity)) < 10);
+            }
+
+            for i in 0..2 {
+                current[i] += dt / inductance * (voltage[i] - resistance * current[i] - emf[i]);
+            }
+            angle = (angle + velocity * dt) % core::f32::consts::TAU;
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_inductance() {
+        let mut motor =
+            MotorParameters::new(I16F16::ONE, I16F16::ONE, I16F16::ZERO, I16F16::ONE, 7);
+        let gain = I16F16::from_num(20);
+        let observer = SlidingModeObserver::new(&motor, gain, I16F16::ONE, I16F16::ONE);
+        assert_eq!(observer.err(), Some(ObserverError::InvalidInductance));
+
+        // The smallest inductance saturates instead of overflowing
+        motor.q_inductance = I16F16::DELTA;
+        let mut observer =
+            SlidingModeObserver::new(&motor, gain, I16F16::ONE, I16F16::ONE).unwrap();
+        let voltage = TwoPhaseReferenceFrame {
+            alpha: I16F16::from_num(10),
+            beta: I16F16::ZERO,
+        };
+        let current = TwoPhaseReferenceFrame {
+            alpha: I16F16::ZERO,
+            beta: I16F16::ZERO,
+        };
+        for _ in 0..4 {
+            observer.update(&voltage, &current, I16F16::ONE);
+        }
+        assert!(observer.back_emf().alpha.abs() <= gain);
+    }
+}
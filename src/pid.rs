@@ -2,10 +2,63 @@
 
 use fixed::types::I16F16;
 
+use crate::KILO;
+
+/// The strategy used to stop the integral term from winding up while the
+/// controller output is saturated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AntiWindup {
+    /// Conditional integration. The integrator is frozen whenever the output
+    /// is saturated and the error would push it further into saturation.
+    Clamping,
+    /// Back-calculation. The difference between the saturated and unsaturated
+    /// output is fed back into the integrator, scaled by the tracking gain
+    /// (in units of 1/time, consistent with `dt`).
+    BackCalculation { tracking_gain: I16F16 },
+    /// The integral term is clamped to `±limit`, independently of the output
+    /// limits. The sign of `limit` is ignored.
+    IntegratorLimit { limit: I16F16 },
+}
+
+/// The highest current loop bandwidth in rad/s for the given loop period in
+/// seconds.
+///
+/// The measurement and PWM update add a delay of around 1.5 periods, which
+/// reduces the phase margin at the crossover frequency. This is the
+/// bandwidth which keeps the phase margin above 60°.
+pub fn max_current_bandwidth(period: I16F16) -> I16F16 {
+    if period <= I16F16::ZERO {
+        return I16F16::MAX;
+    }
+    (I16F16::PI / 9).saturating_div(period)
+}
+
+/// Create the flux (d-axis) and torque (q-axis) current controllers for a
+/// motor with [`PIController::current_loop`], with the bandwidth in rad/s and
+/// loop period in seconds.
+pub(crate) fn current_loops(
+    resistance: I16F16,
+    d_inductance: I16F16,
+    q_inductance: I16F16,
+    bandwidth: I16F16,
+    period: I16F16,
+) -> (PIController, PIController) {
+    (
+        PIController::current_loop(resistance, d_inductance, bandwidth, period),
+        PIController::current_loop(resistance, q_inductance, bandwidth, period),
+    )
+}
+
 /// A fixed-point PI controller.
+///
+/// By default the output is unbounded. Use [`PIController::with_output_limits`]
+/// or [`PIController::set_output_limits`] to bound it, in which case the
+/// integrator is protected from windup according to the configured
+/// [`AntiWindup`] strategy.
 pub struct PIController {
     k_p: I16F16,
     integral: IntegralComponent,
+    limits: OutputLimits,
 }
 
 impl PIController {
@@ -13,17 +66,121 @@ impl PIController {
     pub fn new(k_p: I16F16, k_i: I16F16) -> Self {
         Self {
             k_p,
-            integral: IntegralComponent {
-                k_i,
-                integral: I16F16::ZERO,
-            },
+            integral: IntegralComponent::new(k_i),
+            limits: OutputLimits::default(),
         }
     }
 
+    /// Create a current controller for one axis of a motor, which outputs a
+    /// voltage in volts.
+    ///
+    /// The zero of the controller cancels the pole of the motor, so that the
+    /// closed loop is first-order with the given bandwidth (`k_p = L·ωc`,
+    /// `k_i = R·ωc`). The gains are negative, as the controller acts on
+    /// `measurement - setpoint`.
+    ///
+    /// The bandwidth is limited to [`max_current_bandwidth`] for the loop
+    /// period.
+    ///
+    /// Params:
+    /// - `resistance`: phase resistance in ohms
+    /// - `inductance`: inductance of the axis in millihenries
+    /// - `bandwidth`: closed loop bandwidth in rad/s
+    /// - `period`: time between updates in seconds
+    pub fn current_loop(
+        resistance: I16F16,
+        inductance: I16F16,
+        bandwidth: I16F16,
+        period: I16F16,
+    ) -> Self {
+        let bandwidth = bandwidth.min(max_current_bandwidth(period));
+        Self::new(
+            -(inductance.saturating_mul(bandwidth) / KILO),
+            -resistance.saturating_mul(bandwidth),
+        )
+    }
+
+    /// Create a velocity controller, which takes a mechanical velocity in
+    /// rad/s and outputs a q-axis current in amps.
+    ///
+    /// The bandwidth of the velocity loop is the current loop bandwidth
+    /// divided by `ratio`, so that the current loop is fast enough to be
+    /// ignored, and the zero of the controller is the same ratio below that
+    /// to keep a good phase margin (around 60° for a ratio of 4). The gains
+    /// are negative, as the controller acts on `measurement - setpoint`.
+    ///
+    /// Params:
+    /// - `inertia`: rotor and load inertia in g·m²
+    /// - `torque_constant`: torque per amp of q-axis current in N·m/A
+    /// - `current_bandwidth`: bandwidth of the current loop in rad/s
+    /// - `ratio`: the ratio of the current and velocity loop bandwidths
+    pub fn velocity_loop(
+        inertia: I16F16,
+        torque_constant: I16F16,
+        current_bandwidth: I16F16,
+        ratio: I16F16,
+    ) -> Self {
+        let ratio = ratio.max(I16F16::ONE);
+        let bandwidth = current_bandwidth / ratio;
+        let k_p = inertia
+            .saturating_mul(bandwidth)
+            .saturating_div(torque_constant)
+            / KILO;
+        Self::new(-k_p, -k_p.saturating_mul(bandwidth / ratio))
+    }
+
+    /// Set the initial output limits of the controller.
+    pub fn with_output_limits(mut self, min: I16F16, max: I16F16) -> Self {
+        self.set_output_limits(min, max);
+        self
+    }
+
+    /// Set the anti-windup strategy of the controller. Defaults to
+    /// [`AntiWindup::Clamping`].
+    pub fn with_anti_windup(mut self, anti_windup: AntiWindup) -> Self {
+        self.integral.anti_windup = anti_windup;
+        self
+    }
+
+    /// Change the output limits of the controller.
+    ///
+    /// This can be called between updates, e.g. to track a changing bus
+    /// voltage. Inverted limits are swapped.
+    pub fn set_output_limits(&mut self, min: I16F16, max: I16F16) {
+        self.limits.set(min, max);
+    }
+
+    /// The current `(min, max)` output limits of the controller.
+    pub fn output_limits(&self) -> (I16F16, I16F16) {
+        (self.limits.min, self.limits.max)
+    }
+
+    /// Whether the output was limited during the last update.
+    pub fn is_saturated(&self) -> bool {
+        self.limits.saturated
+    }
+
+    /// Reset the controller so that it outputs `output` when the error is
+    /// zero.
+    ///
+    /// This can be used to switch to this controller without a bump in its
+    /// output.
+    pub fn reset_to(&mut self, output: I16F16) {
+        self.integral.integral = output.clamp(self.limits.min, self.limits.max);
+        self.limits.saturated = false;
+    }
+
+    /// Reset the internal state of the controller.
+    pub fn reset(&mut self) {
+        self.reset_to(I16F16::ZERO);
+    }
+
     /// Update the PI controller, returning the new output value.
     pub fn update(&mut self, measurement: I16F16, setpoint: I16F16, dt: I16F16) -> I16F16 {
         let error = measurement - setpoint;
-        self.k_p * error + self.integral.update(error, dt)
+        let proportional = self.k_p.saturating_mul(error);
+        self.integral
+            .update(error, dt, proportional, &mut self.limits)
     }
 }
 
@@ -31,10 +188,13 @@ impl PIController {
 ///
 /// Uses the derivative-on-measurement technique to avoid derivative kicks on
 /// setpoint changes.
+///
+/// Output limits and anti-windup behave the same as for [`PIController`].
 pub struct PIDController {
     k_p: I16F16,
     integral: IntegralComponent,
     derivative: DerivativeComponent,
+    limits: OutputLimits,
 }
 
 impl PIDController {
@@ -42,33 +202,151 @@ impl PIDController {
     pub fn new(k_p: I16F16, k_i: I16F16, k_d: I16F16) -> Self {
         Self {
             k_p,
-            integral: IntegralComponent {
-                k_i,
-                integral: I16F16::ZERO,
-            },
+            integral: IntegralComponent::new(k_i),
             derivative: DerivativeComponent {
                 k_d,
                 last_measurement: None,
             },
+            limits: OutputLimits::default(),
         }
     }
 
+    /// Set the initial output limits of the controller.
+    pub fn with_output_limits(mut self, min: I16F16, max: I16F16) -> Self {
+        self.set_output_limits(min, max);
+        self
+    }
+
+    /// Set the anti-windup strategy of the controller. Defaults to
+    /// [`AntiWindup::Clamping`].
+    pub fn with_anti_windup(mut self, anti_windup: AntiWindup) -> Self {
+        self.integral.anti_windup = anti_windup;
+        self
+    }
+
+    /// Change the output limits of the controller. Inverted limits are
+    /// swapped.
+    pub fn set_output_limits(&mut self, min: I16F16, max: I16F16) {
+        self.limits.set(min, max);
+    }
+
+    /// The current `(min, max)` output limits of the controller.
+    pub fn output_limits(&self) -> (I16F16, I16F16) {
+        (self.limits.min, self.limits.max)
+    }
+
+    /// Whether the output was limited during the last update.
+    pub fn is_saturated(&self) -> bool {
+        self.limits.saturated
+    }
+
+    /// Reset the controller so that it outputs `output` when the error is
+    /// zero.
+    ///
+    /// This can be used to switch to this controller without a bump in its
+    /// output.
+    pub fn reset_to(&mut self, output: I16F16) {
+        self.integral.integral = output.clamp(self.limits.min, self.limits.max);
+        self.derivative.last_measurement = None;
+        self.limits.saturated = false;
+    }
+
+    /// Reset the internal state of the controller.
+    pub fn reset(&mut self) {
+        self.reset_to(I16F16::ZERO);
+    }
+
     /// Update the PID controller, returning the new output value.
     pub fn update(&mut self, measurement: I16F16, setpoint: I16F16, dt: I16F16) -> I16F16 {
         let error = measurement - setpoint;
-        self.k_p * error + self.integral.update(error, dt) + self.derivative.update(measurement, dt)
+        let proportional = self
+            .k_p
+            .saturating_mul(error)
+            .saturating_add(self.derivative.update(measurement, dt));
+        self.integral
+            .update(error, dt, proportional, &mut self.limits)
+    }
+}
+
+struct OutputLimits {
+    min: I16F16,
+    max: I16F16,
+    saturated: bool,
+}
+
+impl OutputLimits {
+    /// Set the limits, swapping them if they are inverted.
+    fn set(&mut self, min: I16F16, max: I16F16) {
+        self.min = min.min(max);
+        self.max = max.max(min);
+    }
+}
+
+impl Default for OutputLimits {
+    fn default() -> Self {
+        Self {
+            min: I16F16::MIN,
+            max: I16F16::MAX,
+            saturated: false,
+        }
     }
 }
 
 struct IntegralComponent {
     k_i: I16F16,
     integral: I16F16,
+    anti_windup: AntiWindup,
 }
 
 impl IntegralComponent {
-    fn update(&mut self, error: I16F16, dt: I16F16) -> I16F16 {
-        self.integral += self.k_i * error * dt;
-        self.integral
+    fn new(k_i: I16F16) -> Self {
+        Self {
+            k_i,
+            integral: I16F16::ZERO,
+            anti_windup: AntiWindup::Clamping,
+        }
+    }
+
+    /// Integrate the error, returning the limited controller output.
+    ///
+    /// `other_terms` is the sum of the non-integral terms of the controller.
+    fn update(
+        &mut self,
+        error: I16F16,
+        dt: I16F16,
+        other_terms: I16F16,
+        limits: &mut OutputLimits,
+    ) -> I16F16 {
+        let increment = self.k_i.saturating_mul(error).saturating_mul(dt);
+
+        match self.anti_windup {
+            AntiWindup::Clamping => {
+                let unlimited = other_terms.saturating_add(self.integral.saturating_add(increment));
+                let winding_up = (unlimited > limits.max && increment.is_positive())
+                    || (unlimited < limits.min && increment.is_negative());
+                if !winding_up {
+                    self.integral = self.integral.saturating_add(increment);
+                }
+            }
+            AntiWindup::BackCalculation { tracking_gain } => {
+                let unlimited = other_terms.saturating_add(self.integral);
+                let excess = unlimited.clamp(limits.min, limits.max) - unlimited;
+                let correction = tracking_gain.saturating_mul(excess).saturating_mul(dt);
+                self.integral = self
+                    .integral
+                    .saturating_add(increment)
+                    .saturating_add(correction);
+            }
+            AntiWindup::IntegratorLimit { limit } => {
+                let limit = limit.saturating_abs();
+                self.integral = self.integral.saturating_add(increment).clamp(-limit, limit);
+            }
+        }
+
+        let unlimited = other_terms.saturating_add(self.integral);
+        let output = unlimited.clamp(limits.min, limits.max);
+        limits.saturated = output != unlimited;
+        output
     }
 }
 
@@ -89,3 +367,145 @@ impl DerivativeComponent {
         self.k_d * derivative
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DT: I16F16 = I16F16::lit("0.01");
+
+    #[test]
+    fn output_is_limited() {
+        let mut pi = PIController::new(I16F16::from_num(-10), I16F16::ZERO)
+            .with_output_limits(I16F16::from_num(-2), I16F16::from_num(3));
+
+        assert_eq!(pi.update(I16F16::ZERO, I16F16::ONE, DT), 3);
+        assert!(pi.is_saturated());
+        assert_eq!(pi.update(I16F16::ONE, I16F16::ZERO, DT), -2);
+        assert!(pi.is_saturated());
+        assert_eq!(pi.update(I16F16::ZERO, I16F16::lit("0.25"), DT), 2.5);
+        assert!(!pi.is_saturated());
+    }
+
+    #[test]
+    fn clamping_freezes_integrator() {
+        let mut pi = PIController::new(I16F16::ZERO, I16F16::from_num(-100))
+            .with_output_limits(-I16F16::ONE, I16F16::ONE);
+
+        for _ in 0..1000 {
+            pi.update(I16F16::ZERO, I16F16::ONE, DT);
+        }
+        assert!(pi.integral.integral <= I16F16::ONE);
+
+        // The output should come out of saturation as soon as the error
+        // reverses.
+        let output = pi.update(I16F16::ONE, I16F16::ZERO, DT);
+        assert!(output < I16F16::ONE);
+    }
+
+    #[test]
+    fn back_calculation_tracks_limit() {
+        let mut pi = PIController::new(I16F16::ZERO, I16F16::from_num(-10))
+            .with_output_limits(-I16F16::ONE, I16F16::ONE)
+            .with_anti_windup(AntiWindup::BackCalculation {
+                tracking_gain: I16F16::from_num(50),
+            });
+
+        for _ in 0..1000 {
+            pi.update(I16F16::ZERO, I16F16::ONE, DT);
+        }
+
+        // The integrator settles where the tracking term cancels the error
+        // term, just above the limit.
+        let integral = pi.integral.integral;
+        assert!(integral > I16F16::ONE && integral < I16F16::lit("1.3"));
+    }
+
+    #[test]
+    fn integrator_limit() {
+        let mut pid = PIDController::new(I16F16::ZERO, I16F16::from_num(-100), I16F16::ZERO)
+            .with_anti_windup(AntiWindup::IntegratorLimit {
+                limit: I16F16::lit("0.5"),
+            });
+
+        for _ in 0..1000 {
+            pid.update(I16F16::ZERO, I16F16::ONE, DT);
+        }
+        assert_eq!(
+            pid.update(I16F16::ZERO, I16F16::ONE, DT),
+            I16F16::lit("0.5")
+        );
+        assert!(!pid.is_saturated());
+    }
+
+    #[test]
+    fn inverted_limits() {
+        let mut pi = PIController::new(I16F16::from_num(-10), I16F16::ZERO)
+            .with_output_limits(I16F16::ONE, -I16F16::ONE);
+        assert_eq!(pi.output_limits(), (-I16F16::ONE, I16F16::ONE));
+        assert_eq!(pi.update(I16F16::ZERO, I16F16::ONE, DT), 1);
+
+        let mut pid = PIDController::new(I16F16::ZERO, I16F16::from_num(-100), I16F16::ZERO)
+            .with_anti_windup(AntiWindup::IntegratorLimit {
+                limit: I16F16::lit("-0.5"),
+            });
+        pid.set_output_limits(I16F16::MAX, I16F16::MIN);
+        for _ in 0..1000 {
+            pid.update(I16F16::ZERO, I16F16::ONE, DT);
+        }
+        assert_eq!(
+            pid.update(I16F16::ZERO, I16F16::ONE, DT),
+            I16F16::lit("0.5")
+        );
+    }
+
+    #[test]
+    fn current_loop_gains() {
+        let period = I16F16::from_num(1. / 16384.);
+        let pi = PIController::current_loop(
+            I16F16::lit("0.5"),
+            I16F16::lit("0.2"),
+            I16F16::from_num(2000),
+            period,
+        );
+        assert!(pi.k_p.abs_diff(I16F16::lit("-0.4")) < 0.001);
+        assert_eq!(pi.integral.k_i, -1000);
+
+        // A slow loop limits the bandwidth
+        let pi = PIController::current_loop(
+            I16F16::lit("0.5"),
+            I16F16::lit("0.2"),
+            I16F16::from_num(2000),
+            I16F16::from_num(1. / 1024.),
+        );
+        assert!(pi.integral.k_i.abs_diff(I16F16::from_num(-0.5 * 357.45)) < 0.1);
+    }
+
+    #[test]
+    fn velocity_loop_tracks() {
+        let (inertia, torque_constant) = (I16F16::lit("0.02"), I16F16::lit("0.0525"));
+        let mut pi = PIController::velocity_loop(
+            inertia,
+            torque_constant,
+            I16F16::from_num(2000),
+            I16F16::from_num(4),
+        );
+        assert!(pi.k_p.abs_diff(I16F16::lit("-0.1905")) < 0.001);
+
+        // Assume the current loop is ideal
+        let dt = 1. / 16384.;
+        let mut velocity = 0f32;
+        let mut peak = 0f32;
+        for _ in 0..1638 {
+            let current = pi.update(
+                I16F16::from_num(velocity),
+                I16F16::from_num(10),
+                I16F16::from_num(dt),
+            );
+            velocity += current.to_num::<f32>() * 0.0525 / 2e-5 * dt;
+            peak = peak.max(velocity);
+        }
+        assert!((velocity - 10.).abs() < 0.2, "{velocity}");
+        assert!(peak < 13., "{peak}");
+    }
+}
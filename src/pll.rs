@@ -0,0 +1,240 @@
+//! Phase-locked loop for tracking an angle and its derivatives.
+//!
+//! A [`Pll`] filters a noisy angle measurement, e.g. from an encoder or an
+//! [`observer`](crate::observer), and estimates the velocity and acceleration
+//! without differentiating the measurement directly.
+
+use fixed::types::{I16F16, I32F32};
+
+use crate::angle;
+
+/// A type-2 phase-locked loop, which tracks an angle with zero steady-state
+/// error at constant velocity.
+///
+/// The gains are derived from the natural frequency (bandwidth) and damping
+/// ratio of the loop, with a proportional gain of `2ζω` and an integral gain
+/// of `ω²`. At constant acceleration `a`, the angle lags by `a/ω²` and the
+/// velocity lags by `2ζa/ω`.
+#[derive(Debug, Clone)]
+pub struct Pll {
+    bandwidth: I16F16,
+    damping: I16F16,
+    locked: bool,
+    /// The angle and velocity are kept at a higher precision than their
+    /// outputs, so that small corrections are not rounded away.
+    angle: I32F32,
+    velocity: I32F32,
+    acceleration: I16F16,
+}
+
+impl Pll {
+    /// Create a new PLL with the given bandwidth in rad/s, and a damping ratio
+    /// of one.
+    pub fn new(bandwidth: I16F16) -> Self {
+        Self {
+            bandwidth,
+            damping: I16F16::ONE,
+            locked: false,
+            angle: I32F32::ZERO,
+            velocity: I32F32::ZERO,
+            acceleration: I16F16::ZERO,
+        }
+    }
+
+    /// Set the damping ratio of the loop.
+    pub fn with_damping(mut self, damping: I16F16) -> Self {
+        self.damping = damping;
+        self
+    }
+
+    /// Change the bandwidth of the loop in rad/s.
+    pub fn set_bandwidth(&mut self, bandwidth: I16F16) {
+        self.bandwidth = bandwidth;
+    }
+
+    /// The bandwidth of the loop in rad/s.
+    pub fn bandwidth(&self) -> I16F16 {
+        self.bandwidth
+    }
+
+    /// Reset the loop to the given angle (in radians) and velocity (in
+    /// rad/s).
+    pub fn reset_to(&mut self, angle: I16F16, velocity: I16F16) {
+        self.locked = true;
+        self.angle = I32F32::from(angle::wrap(angle));
+        self.velocity = I32F32::from(velocity);
+        self.acceleration = I16F16::ZERO;
+    }
+
+    /// Reset the loop, so that it starts from the next measurement.
+    pub fn reset(&mut self) {
+        self.locked = false;
+        self.angle = I32F32::ZERO;
+        self.velocity = I32F32::ZERO;
+        self.acceleration = I16F16::ZERO;
+    }
+
+    /// Update the loop with a measured angle in radians.
+    ///
+    /// The first measurement after creating or resetting the loop is used as
+    /// the initial angle.
+    pub fn update(&mut self, measured_angle: I16F16, dt: I16F16) {
+        if !self.locked {
+            self.reset_to(measured_angle, I16F16::ZERO);
+            return;
+        }
+
+        self.predict(dt);
+        // The difference is taken at the higher precision of the estimate
+        let error = wrap(I32F32::from(measured_angle) - self.angle + I32F32::PI) - I32F32::PI;
+        self.correct(error, dt);
+    }
+
+    /// Update the loop with the phase error in radians from an external phase
+    /// detector, i.e. the measured angle minus [`Pll::angle`].
+    pub fn update_phase_error(&mut self, error: I16F16, dt: I16F16) {
+        self.locked = true;
+        self.predict(dt);
+        self.correct(I32F32::from(error), dt);
+    }
+
+    /// Update the loop with the sine and cosine of a measured angle, e.g. from
+    /// a resolver or [`cordic::sin_cos`].
+    ///
+    /// The phase error is `sin(measured - estimated)`, so the pair should have
+    /// an amplitude of one, or the bandwidth is scaled by the amplitude.
+    pub fn update_sin_cos(&mut self, sin: I16F16, cos: I16F16, dt: I16F16) {
+        if !self.locked {
+            self.reset_to(angle::atan2(sin, cos), I16F16::ZERO);
+            return;
+        }
+
+        self.predict(dt);
+        let (sin_estimate, cos_estimate) = cordic::sin_cos(self.angle());
+        let error = sin
+            .saturating_mul(cos_estimate)
+            .saturating_sub(cos.saturating_mul(sin_estimate));
+        self.correct(I32F32::from(error), dt);
+    }
+
+    /// Advance the estimated angle to the time of the new measurement.
+    fn predict(&mut self, dt: I16F16) {
+        self.angle = wrap(
+            self.angle
+                .saturating_add(self.velocity.saturating_mul(I32F32::from(dt))),
+        );
+    }
+
+    /// Correct the estimates from the phase error of the new measurement.
+    fn correct(&mut self, error: I32F32, dt: I16F16) {
+        // The gains are applied at the higher precision, so that the square
+        // of a large bandwidth does not overflow, and the product of a small
+        // error and `dt` is not rounded to zero
+        let bandwidth = I32F32::from(self.bandwidth);
+        let bandwidth_error = bandwidth.saturating_mul(error);
+        let acceleration = bandwidth_error.saturating_mul(bandwidth);
+        let wide_dt = I32F32::from(dt);
+        self.velocity = self
+            .velocity
+            .saturating_add(acceleration.saturating_mul(wide_dt))
+            .clamp(I32F32::from(I16F16::MIN), I32F32::from(I16F16::MAX));
+
+        let proportional = I32F32::from(self.damping * 2).saturating_mul(bandwidth_error);
+        self.angle = wrap(
+            self.angle
+                .saturating_add(proportional.saturating_mul(wide_dt)),
+        );
+
+        self.acceleration = crate::low_pass(
+            self.acceleration,
+            I16F16::saturating_from_num(acceleration),
+            self.bandwidth,
+            dt,
+        );
+    }
+
+    /// The filtered angle in radians, in `[0, 2π)`.
+    pub fn angle(&self) -> I16F16 {
+        angle::wrap(self.angle.to_num())
+    }
+
+    /// The estimated velocity in rad/s.
+    pub fn velocity(&self) -> I16F16 {
+        self.velocity.to_num()
+    }
+
+    /// The estimated acceleration in rad/s², low-pass filtered at the
+    /// bandwidth of the loop.
+    pub fn acceleration(&self) -> I16F16 {
+        self.acceleration
+    }
+}
+
+/// Wrap an angle in radians to `[0, 2π)`.
+fn wrap(angle: I32F32) -> I32F32 {
+    angle.rem_euclid(I32F32::TAU)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tracks_acceleration_across_wrap() {
+        let mut pll = Pll::new(I16F16::from_num(200));
+        let dt = 1. / 16384.;
+        let acceleration = 200f32;
+        let mut velocity = 0f32;
+        let mut angle = 5f32;
+
+        for step in 0..16384 {
+            pll.update(I16F16::from_num(angle), I16F16::from_num(dt));
+
+            if step > 8192 {
+                let error = angle::difference(pll.angle(), I16F16::from_num(angle));
+                assert!(error.abs() < 0.01, "angle error {error}");
+                // Lags by 2a/ω at constant acceleration
+                assert!(pll.velocity().abs_diff(I16F16::from_num(velocity)) < 3);
+                assert!(pll.acceleration().abs_diff(I16F16::from_num(acceleration)) < 30);
+            }
+
+            velocity += acceleration * dt;
+            angle = (angle + velocity * dt) % core::f32::consts::TAU;
+        }
+    }
+
+    #[test]
+    fn corrects_small_errors() {
+        let mut pll = Pll::new(I16F16::from_num(100));
+        let dt = I16F16::from_num(1. / 16384.);
+        pll.update(I16F16::ONE, dt);
+
+        // The error is too small to move the angle within a single update
+        let measured = I16F16::lit("1.0005");
+        for _ in 0..16384 {
+            pll.update(measured, dt);
+        }
+        assert!(pll.angle().abs_diff(measured) < 0.0001, "{}", pll.angle());
+        assert!(pll.velocity().abs() < 0.01, "{}", pll.velocity());
+    }
+
+    #[test]
+    fn tracks_sin_cos() {
+        let mut pll = Pll::new(I16F16::from_num(500)).with_damping(I16F16::lit("0.7"));
+        let dt = I16F16::from_num(1. / 16384.);
+        let velocity = I16F16::from_num(-300);
+        let mut angle = I16F16::ONE;
+
+        for step in 0..8192 {
+            let (sin, cos) = cordic::sin_cos(angle);
+            pll.update_sin_cos(sin, cos, dt);
+
+            if step > 4096 {
+                assert!(angle::difference(pll.angle(), angle).abs() < 0.01);
+                assert!(pll.velocity().abs_diff(velocity) < 1);
+            }
+
+            angle = angle::wrap(angle + velocity * dt);
+        }
+    }
+}
@@ -0,0 +1,359 @@
+//! Fault detection for overcurrent, bus voltage, temperature and stall.
+//!
+//! Each check compares a measurement against a threshold on every update, and
+//! only reports a fault once the threshold has been exceeded for a number of
+//! consecutive updates (the debounce count). Checks are disabled until
+//! configured.
+//!
+//! When a fault is reported, the outputs should be disabled or zeroed (see
+//! [`Foc::zero_output`](crate::Foc::zero_output)).
+
+use fixed::types::{I16F16, I32F32};
+
+use crate::park_clarke::{self, ThreePhaseBalancedReferenceFrame};
+
+/// A fault detected by [`Protection`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// The instantaneous current in a phase was too high.
+    Overcurrent,
+    /// The RMS phase current was too high.
+    RmsOvercurrent,
+    /// The bus voltage was too high.
+    Overvoltage,
+    /// The bus voltage was too low.
+    Undervoltage,
+    /// The temperature of the power stage was too high.
+    FetOvertemperature,
+    /// The temperature of the motor was too high.
+    MotorOvertemperature,
+    /// The current was high while the rotor was not moving.
+    Stall,
+}
+
+/// The measurements checked on each update.
+#[derive(Debug, Clone)]
+pub struct Measurements {
+    /// Phase currents of A and B in amps.
+    pub currents: [I16F16; 2],
+    /// DC link voltage in volts.
+    pub bus_voltage: I16F16,
+    /// Temperature of the power stage in °C, if measured.
+    pub fet_temperature: Option<I16F16>,
+    /// Temperature of the motor in °C, if measured.
+    pub motor_temperature: Option<I16F16>,
+    /// Electrical velocity in rad/s.
+    pub velocity: I16F16,
+}
+
+/// A threshold which must be exceeded for a number of consecutive updates.
+#[derive(Debug, Clone)]
+struct Check {
+    threshold: I16F16,
+    debounce: u16,
+    count: u16,
+}
+
+impl Check {
+    fn new(threshold: I16F16, debounce: u16) -> Self {
+        Self {
+            threshold,
+            debounce: debounce.max(1),
+            count: 0,
+        }
+    }
+
+    /// Update the check, returning whether it has tripped.
+    fn update(&mut self, exceeded: bool) -> bool {
+        self.count = if exceeded {
+            self.count.saturating_add(1)
+        } else {
+            0
+        };
+        self.count >= self.debounce
+    }
+}
+
+/// Checks measurements against configurable thresholds.
+#[derive(Debug, Clone)]
+pub struct Protection {
+    overcurrent: Option<Check>,
+    rms_overcurrent: Option<Check>,
+    rms_bandwidth: I16F16,
+    mean_square_current: I32F32,
+    overvoltage: Option<Check>,
+    undervoltage: Option<Check>,
+    fet_overtemperature: Option<Check>,
+    motor_overtemperature: Option<Check>,
+    stall: Option<Check>,
+    stall_velocity: I16F16,
+}
+
+impl Protection {
+    /// Create a new protection with all checks disabled.
+    pub fn new() -> Self {
+        Self {
+            overcurrent: None,
+            rms_overcurrent: None,
+            rms_bandwidth: I16F16::ZERO,
+            mean_square_current: I32F32::ZERO,
+            overvoltage: None,
+            undervoltage: None,
+            fet_overtemperature: None,
+            motor_overtemperature: None,
+            stall: None,
+            stall_velocity: I16F16::ZERO,
+        }
+    }
+
+    /// Report [`Fault::Overcurrent`] when the magnitude of any phase current
+    /// exceeds `limit` amps for `debounce` consecutive updates.
+    pub fn with_overcurrent(mut self, limit: I16F16, debounce: u16) -> Self {
+        self.overcurrent = Some(Check::new(limit, debounce));
+        self
+    }
+
+    /// Report [`Fault::RmsOvercurrent`] when the RMS phase current exceeds
+    /// `limit` amps for `debounce` consecutive updates.
+    ///
+    /// The mean square current is averaged with a low-pass filter with the
+    /// given bandwidth in rad/s.
+    pub fn with_rms_overcurrent(mut self, limit: I16F16, bandwidth: I16F16, debounce: u16) -> Self {
+        self.rms_overcurrent = Some(Check::new(limit, debounce));
+        self.rms_bandwidth = bandwidth;
+        self
+    }
+
+    /// Report [`Fault::Overvoltage`] when the bus voltage exceeds `limit`
+    /// volts for `debounce` consecutive updates.
+    pub fn with_overvoltage(mut self, limit: I16F16, debounce: u16) -> Self {
+        self.overvoltage = Some(Check::new(limit, debounce));
+        self
+    }
+
+    /// Report [`Fault::Undervoltage`] when the bus voltage is below `limit`
+    /// volts for `debounce` consecutive updates.
+    pub fn with_undervoltage(mut self, limit: I16F16, debounce: u16) -> Self {
+        self.undervoltage = Some(Check::new(limit, debounce));
+        self
+    }
+
+    /// Report [`Fault::FetOvertemperature`] when the power stage temperature
+    /// exceeds `limit` °C for `debounce` consecutive updates.
+    pub fn with_fet_overtemperature(mut self, limit: I16F16, debounce: u16) -> Self {
+        self.fet_overtemperature = Some(Check::new(limit, debounce));
+        self
+    }
+
+    /// Report [`Fault::MotorOvertemperature`] when the motor temperature
+    /// exceeds `limit` °C for `debounce` consecutive updates.
+    pub fn with_motor_overtemperature(mut self, limit: I16F16, debounce: u16) -> Self {
+        self.motor_overtemperature = Some(Check::new(limit, debounce));
+        self
+    }
+
+    /// Report [`Fault::Stall`] when the current magnitude exceeds `current`
+    /// amps while the electrical velocity is below `velocity` rad/s, for
+    /// `debounce` consecutive updates.
+    pub fn with_stall(mut self, current: I16F16, velocity: I16F16, debounce: u16) -> Self {
+        self.stall = Some(Check::new(current, debounce));
+        self.stall_velocity = velocity;
+        self
+    }
+
+    /// The filtered RMS phase current in amps.
+    pub fn rms_current(&self) -> I16F16 {
+        crate::sqrt_wide(self.mean_square_current)
+    }
+
+    /// Reset the debounce counts and the RMS current.
+    pub fn reset(&mut self) {
+        for check in [
+            &mut self.overcurrent,
+            &mut self.rms_overcurrent,
+            &mut self.overvoltage,
+            &mut self.undervoltage,
+            &mut self.fet_overtemperature,
+            &mut self.motor_overtemperature,
+            &mut self.stall,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            check.count = 0;
+        }
+        self.mean_square_current = I32F32::ZERO;
+    }
+
+    /// Check the measurements, returning the first fault that has tripped.
+    ///
+    /// `dt` is the time since the last update in seconds. All checks are
+    /// updated on every call, so that their debounce counts stay up to date.
+    pub fn update(&mut self, measurements: &Measurements, dt: I16F16) -> Result<(), Fault> {
+        let [a, b] = measurements.currents;
+        let c = -(a.saturating_add(b));
+        let peak = a
+            .saturating_abs()
+            .max(b.saturating_abs())
+            .max(c.saturating_abs());
+
+        // The magnitude of the stationary frame current is the amplitude of
+        // the phase currents
+        let current = park_clarke::clarke(ThreePhaseBalancedReferenceFrame { a, b });
+        let square_amplitude =
+            crate::square(current.alpha).saturating_add(crate::square(current.beta));
+        let alpha = I32F32::from(self.rms_bandwidth.saturating_mul(dt).min(I16F16::ONE));
+        self.mean_square_current = self.mean_square_current.saturating_add(
+            alpha.saturating_mul((square_amplitude / 2).saturating_sub(self.mean_square_current)),
+        );
+        let rms = self.rms_current();
+
+        let current_magnitude = crate::sqrt_wide(square_amplitude);
+        let bus_voltage = measurements.bus_voltage;
+        let stall_velocity = self.stall_velocity;
+        let trips = [
+            (
+                trip(&mut self.overcurrent, |limit| peak > limit),
+                Fault::Overcurrent,
+            ),
+            (
+                trip(&mut self.rms_overcurrent, |limit| rms > limit),
+                Fault::RmsOvercurrent,
+            ),
+            (
+                trip(&mut self.overvoltage, |limit| bus_voltage > limit),
+                Fault::Overvoltage,
+            ),
+            (
+                trip(&mut self.undervoltage, |limit| bus_voltage < limit),
+                Fault::Undervoltage,
+            ),
+            (
+                trip(&mut self.fet_overtemperature, |limit| {
+                    measurements.fet_temperature.is_some_and(|t| t > limit)
+                }),
+                Fault::FetOvertemperature,
+            ),
+            (
+                trip(&mut self.motor_overtemperature, |limit| {
+                    measurements.motor_temperature.is_some_and(|t| t > limit)
+                }),
+                Fault::MotorOvertemperature,
+            ),
+            (
+                trip(&mut self.stall, |limit| {
+                    current_magnitude > limit
+                        && measurements.velocity.saturating_abs() < stall_velocity
+                }),
+                Fault::Stall,
+            ),
+        ];
+
+        match trips.into_iter().find(|(tripped, _)| *tripped) {
+            Some((_, fault)) => Err(fault),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Update a check if it is enabled, returning whether it has tripped.
+fn trip(check: &mut Option<Check>, exceeded: impl FnOnce(I16F16) -> bool) -> bool {
+    match check {
+        Some(check) => {
+            let exceeded = exceeded(check.threshold);
+            check.update(exceeded)
+        }
+        None => false,
+    }
+}
+
+impl Default for Protection {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn measurements(currents: [f32; 2], velocity: f32) -> Measurements {
+        Measurements {
+            currents: currents.map(I16F16::from_num),
+            bus_voltage: I16F16::from_num(24),
+            fet_temperature: Some(I16F16::from_num(40)),
+            motor_temperature: None,
+            velocity: I16F16::from_num(velocity),
+        }
+    }
+
+    #[test]
+    fn debounces_overcurrent() {
+        let mut protection = Protection::new()
+            .with_overcurrent(I16F16::from_num(20), 3)
+            .with_undervoltage(I16F16::from_num(10), 1)
+            .with_fet_overtemperature(I16F16::from_num(80), 1)
+            .with_motor_overtemperature(I16F16::from_num(80), 1);
+        let dt = I16F16::from_num(1. / 16384.);
+
+        // Phase C carries 25 A
+        let high = measurements([-12., -13.], 0.);
+        assert_eq!(protection.update(&high, dt), Ok(()));
+        assert_eq!(protection.update(&high, dt), Ok(()));
+        assert_eq!(protection.update(&measurements([1., 1.], 0.), dt), Ok(()));
+        assert_eq!(protection.update(&high, dt), Ok(()));
+        assert_eq!(protection.update(&high, dt), Ok(()));
+        assert_eq!(protection.update(&high, dt), Err(Fault::Overcurrent));
+
+        let mut low_voltage = measurements([1., 1.], 0.);
+        low_voltage.bus_voltage = I16F16::from_num(9);
+        assert_eq!(
+            protection.update(&low_voltage, dt),
+            Err(Fault::Undervoltage)
+        );
+    }
+
+    #[test]
+    fn rms_and_stall() {
+        let mut protection = Protection::new()
+            .with_rms_overcurrent(I16F16::from_num(10), I16F16::from_num(20), 1)
+            .with_stall(I16F16::from_num(5), I16F16::from_num(50), 100);
+        let dt = 1. / 16384.;
+
+        // 12 A peak is 8.5 A RMS
+        let mut angle = 0f32;
+        for _ in 0..16384 {
+            let currents = [
+                12. * angle.cos(),
+                12. * (angle - 2. * core::f32::consts::FRAC_PI_3).cos(),
+            ];
+            assert_eq!(
+                protection.update(&measurements(currents, 300.), I16F16::from_num(dt)),
+                Ok(())
+            );
+            angle += 300. * dt;
+        }
+        assert!(protection.rms_current().abs_diff(I16F16::from_num(8.485)) < 0.1);
+
+        // The same current without moving is a stall
+        let stalled = measurements([12., -6.], 0.);
+        for _ in 0..99 {
+            assert_eq!(protection.update(&stalled, I16F16::from_num(dt)), Ok(()));
+        }
+        assert_eq!(
+            protection.update(&stalled, I16F16::from_num(dt)),
+            Err(Fault::Stall)
+        );
+
+        // Large currents do not saturate the RMS current
+        let mut protection =
+            Protection::new().with_rms_overcurrent(I16F16::from_num(200), I16F16::from_num(100), 1);
+        let large = measurements([300., -150.], 0.);
+        let mut result = Ok(());
+        for _ in 0..16384 {
+            result = protection.update(&large, I16F16::from_num(dt));
+        }
+        assert!(protection.rms_current().abs_diff(I16F16::from_num(212.13)) < 0.5);
+        assert_eq!(result, Err(Fault::RmsOvercurrent));
+    }
+}
@@ -8,21 +8,39 @@ use fixed::types::I16F16;
 
 use crate::park_clarke::TwoPhaseReferenceFrame;
 
+pub mod single_shunt;
+
 pub trait Modulation {
+    /// The largest phase voltage amplitude that can be produced without
+    /// distortion, as a fraction of the bus voltage.
+    ///
+    /// An input vector with a magnitude of 1 corresponds to this voltage.
+    const MODULATION_INDEX: I16F16 = I16F16::lit("0.5");
+
+    /// The largest phase voltage amplitude that can be produced without
+    /// distortion for the given bus voltage.
+    fn max_voltage(bus_voltage: I16F16) -> I16F16 {
+        bus_voltage * Self::MODULATION_INDEX
+    }
+
     fn modulate(value: TwoPhaseReferenceFrame) -> [I16F16; 3];
 
     /// Module the value, returning the result as a value between 0 and the specified
     /// maximum value inclusive.
     fn as_compare_value<const MAX: u16>(value: TwoPhaseReferenceFrame) -> [u16; 3] {
-        Self::modulate(value).map(|val| {
-            (((val + I16F16::from_num(1)) * (MAX as i32 + 1)) / 2)
-                .round()
-                .saturating_to_num::<u16>()
-                .clamp(0, MAX)
-        })
+        Self::modulate(value).map(to_compare_value::<MAX>)
     }
 }
 
+/// Convert a value between -1 and 1 into a value between 0 and the specified
+/// maximum value inclusive.
+fn to_compare_value<const MAX: u16>(value: I16F16) -> u16 {
+    (((value + I16F16::from_num(1)) * (MAX as i32 + 1)) / 2)
+        .round()
+        .saturating_to_num::<u16>()
+        .clamp(0, MAX)
+}
+
 /// Generate PWM values based on a space-vector method.
 ///
 /// This method results in a waveform that is more efficient than sinusoidal
@@ -32,8 +50,9 @@ pub trait Modulation {
 /// Returns a value between -1 and 1 for each channel.
 pub struct SpaceVector;
 
-impl Modulation for SpaceVector {
-    fn modulate(value: TwoPhaseReferenceFrame) -> [I16F16; 3] {
+impl SpaceVector {
+    /// Modulate the value, also returning the sector that it falls in.
+    pub fn modulate_with_sector(value: TwoPhaseReferenceFrame) -> SpaceVectorOutput {
         // Convert alpha/beta to x/y/z
         let sqrt_3_alpha = I16F16::SQRT_3 * value.alpha;
         let beta = value.beta;
@@ -42,37 +61,135 @@ impl Modulation for SpaceVector {
         let z = (beta - sqrt_3_alpha) / 2;
 
         // Calculate which sector the value falls in
-        let sector: u8 = match (x.is_positive(), y.is_positive(), z.is_positive()) {
-            (true, true, false) => 1,
-            (_, true, true) => 2,
-            (true, false, true) => 3,
-            (false, false, true) => 4,
-            (_, false, false) => 5,
-            (false, true, false) => 6,
+        let sector = match (x.is_positive(), y.is_positive(), z.is_positive()) {
+            (true, true, false) => Sector::One,
+            (_, true, true) => Sector::Two,
+            (true, false, true) => Sector::Three,
+            (false, false, true) => Sector::Four,
+            (_, false, false) => Sector::Five,
+            (false, true, false) => Sector::Six,
         };
 
         // Map a,b,c values to three phase
         let (ta, tb, tc);
         match sector {
-            1 | 4 => {
+            Sector::One | Sector::Four => {
                 ta = x - z;
                 tb = x + z;
                 tc = -x + z;
             }
-            2 | 5 => {
+            Sector::Two | Sector::Five => {
                 ta = y - z;
                 tb = y + z;
                 tc = -y - z;
             }
-            3 | 6 => {
+            Sector::Three | Sector::Six => {
                 ta = y - x;
                 tb = -y + x;
                 tc = -y - x;
             }
-            _ => unreachable!("invalid sector"),
         }
 
-        [ta, tb, tc]
+        SpaceVectorOutput {
+            sector,
+            duty: [ta, tb, tc],
+        }
+    }
+
+    /// The channel (0 for A, 1 for B, 2 for C) with the widest high-side duty
+    /// in the given sector.
+    ///
+    /// This is the channel with the shortest low-side on-time, so it cannot be
+    /// reliably sampled by a low-side shunt.
+    pub fn highest_duty_phase(sector: Sector) -> usize {
+        match sector {
+            Sector::One | Sector::Six => 0,
+            Sector::Two | Sector::Three => 1,
+            Sector::Four | Sector::Five => 2,
+        }
+    }
+
+    /// The channel (0 for A, 1 for B, 2 for C) with the narrowest high-side
+    /// duty in the given sector.
+    pub fn lowest_duty_phase(sector: Sector) -> usize {
+        match sector {
+            Sector::Three | Sector::Four => 0,
+            Sector::Five | Sector::Six => 1,
+            Sector::One | Sector::Two => 2,
+        }
+    }
+}
+
+impl Modulation for SpaceVector {
+    const MODULATION_INDEX: I16F16 = crate::FRAC_1_SQRT_3;
+
+    fn modulate(value: TwoPhaseReferenceFrame) -> [I16F16; 3] {
+        Self::modulate_with_sector(value).duty
+    }
+}
+
+/// One of the six 60° sectors of the space-vector hexagon, numbered
+/// anticlockwise from the alpha axis.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sector {
+    /// 0° to 60°.
+    One,
+    /// 60° to 120°.
+    Two,
+    /// 120° to 180°.
+    Three,
+    /// 180° to 240°.
+    Four,
+    /// 240° to 300°.
+    Five,
+    /// 300° to 360°.
+    Six,
+}
+
+impl Sector {
+    /// The sector with the given number, from 1 to 6.
+    ///
+    /// Returns `None` for any other number, e.g. if the sector was stored as
+    /// a byte which has been corrupted.
+    pub fn from_number(number: u8) -> Option<Self> {
+        match number {
+            1 => Some(Self::One),
+            2 => Some(Self::Two),
+            3 => Some(Self::Three),
+            4 => Some(Self::Four),
+            5 => Some(Self::Five),
+            6 => Some(Self::Six),
+            _ => None,
+        }
+    }
+
+    /// The number of the sector, from 1 to 6.
+    pub fn number(self) -> u8 {
+        match self {
+            Self::One => 1,
+            Self::Two => 2,
+            Self::Three => 3,
+            Self::Four => 4,
+            Self::Five => 5,
+            Self::Six => 6,
+        }
+    }
+}
+
+/// The result of space-vector modulation.
+#[derive(Debug, Clone)]
+pub struct SpaceVectorOutput {
+    /// The sector that the vector falls in.
+    pub sector: Sector,
+    /// The value of each channel, between -1 and 1.
+    pub duty: [I16F16; 3],
+}
+
+impl SpaceVectorOutput {
+    /// The duty values as compare values between 0 and the specified maximum
+    /// value inclusive.
+    pub fn as_compare_value<const MAX: u16>(&self) -> [u16; 3] {
+        self.duty.map(to_compare_value::<MAX>)
     }
 }
 
@@ -128,3 +245,31 @@ impl Modulation for Square {
         ]
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sectors() {
+        for number in 1..=6u8 {
+            let sector = Sector::from_number(number).unwrap();
+            assert_eq!(sector.number(), number);
+
+            // The middle of each sector
+            let angle = (f32::from(number) - 0.5) * core::f32::consts::FRAC_PI_3;
+            let output = SpaceVector::modulate_with_sector(TwoPhaseReferenceFrame {
+                alpha: I16F16::from_num(angle.cos() / 2.),
+                beta: I16F16::from_num(angle.sin() / 2.),
+            });
+            assert_eq!(output.sector, sector);
+
+            let highest = SpaceVector::highest_duty_phase(sector);
+            let lowest = SpaceVector::lowest_duty_phase(sector);
+            assert!(output.duty.iter().all(|duty| *duty <= output.duty[highest]));
+            assert!(output.duty.iter().all(|duty| *duty >= output.duty[lowest]));
+        }
+        assert_eq!(Sector::from_number(0), None);
+        assert_eq!(Sector::from_number(7), None);
+    }
+}
@@ -0,0 +1,184 @@
+//! Phase current reconstruction from a single shunt in the DC link.
+//!
+//! This assumes centre-aligned PWM with an up/down counter, where the
+//! high-side switch of a channel is on while the counter is below its compare
+//! value. While counting up, the high-side switches turn off in order of
+//! increasing duty, giving two windows where an active vector is applied:
+//! - Between the lowest and middle compare values, the DC link current is the
+//!   negative of the current in the lowest duty phase.
+//! - Between the middle and highest compare values, the DC link current is the
+//!   current in the highest duty phase.
+//!
+//! When either window is too short to sample, the compare values used while
+//! counting up are shifted to widen it, and the compare values used while
+//! counting down are shifted by the same amount in the opposite direction so
+//! that the average duty of each channel is unchanged. This requires a timer
+//! which can use different compare values while counting up and down (e.g.
+//! by updating them at the counter peak).
+
+use fixed::types::I16F16;
+
+use super::{Sector, SpaceVector, SpaceVectorOutput};
+use crate::park_clarke::ThreePhaseBalancedReferenceFrame;
+
+/// Compare values and sampling points for a single-shunt PWM period.
+#[derive(Debug, Clone)]
+pub struct SingleShuntPwm {
+    /// The space-vector sector of the period.
+    pub sector: Sector,
+    /// Compare values to use while the counter is counting up.
+    pub up_compare: [u16; 3],
+    /// Compare values to use while the counter is counting down.
+    pub down_compare: [u16; 3],
+    /// The counter values (while counting up) at which to sample the shunt
+    /// current, in the middle of each window. `None` if the windows could not
+    /// be made wide enough to sample.
+    pub sample_points: Option<[u16; 2]>,
+}
+
+/// Single-shunt PWM generation for a timer with the specified maximum compare
+/// value.
+pub struct SingleShunt<const MAX: u16> {
+    min_window: u16,
+}
+
+impl<const MAX: u16> SingleShunt<MAX> {
+    /// Create a new single-shunt PWM generator, where `min_window` is the
+    /// minimum window (in timer counts) that a current sample can be taken in.
+    ///
+    /// This should cover the dead time, the settling time of the shunt
+    /// amplifier, and the ADC sampling time.
+    pub fn new(min_window: u16) -> Self {
+        Self { min_window }
+    }
+
+    /// Calculate the compare values and sampling points for the output of the
+    /// space-vector modulator, shifting the phases if required.
+    pub fn compare_values(&self, output: &SpaceVectorOutput) -> SingleShuntPwm {
+        let nominal = output.as_compare_value::<MAX>().map(i32::from);
+        let max = i32::from(MAX);
+        let min_window = i32::from(self.min_window);
+
+        let high = SpaceVector::highest_duty_phase(output.sector);
+        let low = SpaceVector::lowest_duty_phase(output.sector);
+        let mid = 3 - high - low;
+
+        let mut up = nominal;
+        let mut down = nominal;
+
+        // Widen the first window by turning off the lowest phase earlier
+        let shift = (min_window - (up[mid] - up[low]))
+            .max(0)
+            .min(up[low])
+            .min(max - down[low]);
+        up[low] -= shift;
+        down[low] += shift;
+
+        // Widen the second window by turning off the highest phase later
+        let shift = (min_window - (up[high] - up[mid]))
+            .max(0)
+            .min(max - up[high])
+            .min(down[high]);
+        up[high] += shift;
+        down[high] -= shift;
+
+        let sample_points = (up[mid] - up[low] >= min_window && up[high] - up[mid] >= min_window)
+            .then(|| {
+                [
+                    ((up[low] + up[mid]) / 2) as u16,
+                    ((up[mid] + up[high]) / 2) as u16,
+                ]
+            });
+
+        SingleShuntPwm {
+            sector: output.sector,
+            up_compare: up.map(|value| value as u16),
+            down_compare: down.map(|value| value as u16),
+            sample_points,
+        }
+    }
+}
+
+/// Reconstruct the phase currents from the two DC link current samples taken
+/// at [`SingleShuntPwm::sample_points`] during a period in the given sector.
+pub fn reconstruct(samples: [I16F16; 2], sector: Sector) -> ThreePhaseBalancedReferenceFrame {
+    let high = SpaceVector::highest_duty_phase(sector);
+    let low = SpaceVector::lowest_duty_phase(sector);
+    let mid = 3 - high - low;
+
+    let mut currents = [I16F16::ZERO; 3];
+    currents[low] = -samples[0];
+    currents[high] = samples[1];
+    currents[mid] = samples[0].saturating_sub(samples[1]);
+
+    ThreePhaseBalancedReferenceFrame {
+        a: currents[0],
+        b: currents[1],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::park_clarke;
+
+    const MAX: u16 = 1000;
+
+    /// The DC link current while counting up at the given counter value.
+    fn dc_link_current(up_compare: [u16; 3], currents: [f32; 3], counter: u16) -> f32 {
+        (0..3)
+            .filter(|&i| counter < up_compare[i])
+            .map(|i| currents[i])
+            .sum()
+    }
+
+    #[track_caller]
+    fn round_trip(angle: f32, magnitude: f32, min_window: u16) {
+        // Voltage and current vectors in phase, with a balanced current
+        let (sin, cos) = angle.sin_cos();
+        let voltage = park_clarke::TwoPhaseReferenceFrame {
+            alpha: I16F16::from_num(magnitude * cos),
+            beta: I16F16::from_num(magnitude * sin),
+        };
+        let currents = [
+            cos,
+            (angle - 2. * core::f32::consts::FRAC_PI_3).cos(),
+            (angle + 2. * core::f32::consts::FRAC_PI_3).cos(),
+        ];
+        let currents = [currents[0], currents[1], -currents[0] - currents[1]];
+
+        let output = SpaceVector::modulate_with_sector(voltage);
+        let pwm = SingleShunt::<MAX>::new(min_window).compare_values(&output);
+
+        // The average duty of each channel is unchanged
+        let nominal = output.as_compare_value::<MAX>();
+        for ((up, down), nominal) in pwm.up_compare.iter().zip(pwm.down_compare).zip(nominal) {
+            assert_eq!(u32::from(*up) + u32::from(down), 2 * u32::from(nominal));
+        }
+
+        let sample_points = pwm.sample_points.expect("windows too narrow");
+        let samples = sample_points
+            .map(|point| I16F16::from_num(dc_link_current(pwm.up_compare, currents, point)));
+        let reconstructed = reconstruct(samples, pwm.sector);
+
+        assert!(reconstructed.a.abs_diff(I16F16::from_num(currents[0])) < 0.001);
+        assert!(reconstructed.b.abs_diff(I16F16::from_num(currents[1])) < 0.001);
+    }
+
+    #[test]
+    fn reconstruction_across_sectors() {
+        for step in 0..36 {
+            let angle = step as f32 * core::f32::consts::TAU / 36. + 0.05;
+            round_trip(angle, 0.8, 20);
+        }
+    }
+
+    #[test]
+    fn phase_shift_at_low_modulation() {
+        // The windows are almost zero width without phase shifting
+        for step in 0..12 {
+            let angle = step as f32 * core::f32::consts::TAU / 12. + 0.1;
+            round_trip(angle, 0.02, 50);
+        }
+    }
+}
@@ -0,0 +1,273 @@
+//! Six-step (block) commutation, where at any time one phase is driven high,
+//! one is driven low, and one is left floating.
+//!
+//! The commutation step can be selected from an electrical angle, directly
+//! from the Hall state, or without sensors by detecting the zero crossing of
+//! the back-EMF on the floating phase with a [`ZeroCrossingCommutator`].
+//!
+//! There are six steps, numbered 0 to 5. Step `n` applies a voltage vector at
+//! `30° + n·60°`, so advancing the step rotates the vector forwards.
+
+use fixed::types::I16F16;
+
+use crate::{
+    angle,
+    hall::{HallError, HallTable},
+};
+
+/// The angle of one step, 60° in radians.
+const STEP_ANGLE: I16F16 = I16F16::lit("1.0471976");
+
+/// The high and low phases of each step.
+const STEPS: [(usize, usize); 6] = [(0, 2), (1, 2), (1, 0), (2, 0), (2, 1), (0, 1)];
+
+/// The state of a single phase of the bridge.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PhaseState {
+    /// The phase is switched with the duty cycle, with the high-side switch
+    /// on for the duty fraction of the period.
+    High,
+    /// The low-side switch is on for the whole period.
+    Low,
+    /// Both switches are off.
+    Floating,
+}
+
+/// The output of the bridge for one commutation step.
+#[derive(Debug, Clone)]
+pub struct Commutation {
+    /// The commutation step, from 0 to 5.
+    pub step: u8,
+    /// The state of each phase.
+    pub phases: [PhaseState; 3],
+    /// The duty cycle of the high phase, between 0 and 1.
+    pub duty: I16F16,
+}
+
+impl Commutation {
+    /// The commutation for the given step, where a negative duty drives the
+    /// motor backwards by applying the opposite step.
+    pub fn from_step(step: u8, duty: I16F16) -> Self {
+        let step = if duty.is_negative() {
+            (step % 6 + 3) % 6
+        } else {
+            step % 6
+        };
+        let (high, low) = STEPS[usize::from(step)];
+        let mut phases = [PhaseState::Floating; 3];
+        phases[high] = PhaseState::High;
+        phases[low] = PhaseState::Low;
+
+        Self {
+            step,
+            phases,
+            duty: duty.saturating_abs().min(I16F16::ONE),
+        }
+    }
+
+    /// The commutation which applies a voltage vector leading the given
+    /// electrical angle (in radians) by 90° ±30°.
+    pub fn from_angle(electrical_angle: I16F16, duty: I16F16) -> Self {
+        let leading = angle::wrap(electrical_angle + I16F16::FRAC_PI_2);
+        let step = (leading / STEP_ANGLE).to_num::<i32>().clamp(0, 5) as u8;
+        Self::from_step(step, duty)
+    }
+
+    /// The commutation for the given Hall state, based on the angle at the
+    /// centre of its sector.
+    pub fn from_hall(table: &HallTable, state: u8, duty: I16F16) -> Result<Self, HallError> {
+        let angle = table
+            .angle(state)
+            .ok_or(HallError::InvalidState(state & 0b111))?;
+        Ok(Self::from_angle(angle, duty))
+    }
+
+    /// The phase (0 for A, 1 for B, 2 for C) which is floating.
+    pub fn floating_phase(&self) -> usize {
+        self.phases
+            .iter()
+            .position(|&phase| phase == PhaseState::Floating)
+            .unwrap_or(0)
+    }
+
+    /// The compare values between 0 and the specified maximum value
+    /// inclusive, assuming the high-side switch is on while the counter is
+    /// below the compare value.
+    ///
+    /// Both the low and floating phases have a compare value of zero, so the
+    /// outputs of the floating phase must also be disabled.
+    pub fn as_compare_value<const MAX: u16>(&self) -> [u16; 3] {
+        let high = (self.duty * (MAX as i32))
+            .round()
+            .saturating_to_num::<u16>()
+            .min(MAX);
+        self.phases.map(|phase| match phase {
+            PhaseState::High => high,
+            PhaseState::Low | PhaseState::Floating => 0,
+        })
+    }
+}
+
+/// Sensorless commutation by detecting the zero crossing of the back-EMF on
+/// the floating phase.
+///
+/// The back-EMF of the floating phase crosses the neutral voltage halfway
+/// through each step (when driving forwards), so the next commutation happens
+/// after the same time again. The voltage of the floating phase is disturbed
+/// just after commutation while the current in it decays, so crossings during
+/// a blanking time are ignored.
+///
+/// The motor must already be spinning fast enough to produce a measurable
+/// back-EMF, e.g. after an open-loop start.
+pub struct ZeroCrossingCommutator {
+    step: u8,
+    blanking_time: I16F16,
+    time_in_step: I16F16,
+    crossing_time: Option<I16F16>,
+    step_time: Option<I16F16>,
+}
+
+impl ZeroCrossingCommutator {
+    /// Create a new commutator starting at the given step, ignoring crossings
+    /// for `blanking_time` seconds after each commutation.
+    pub fn new(step: u8, blanking_time: I16F16) -> Self {
+        Self {
+            step: step % 6,
+            blanking_time,
+            time_in_step: I16F16::ZERO,
+            crossing_time: None,
+            step_time: None,
+        }
+    }
+
+    /// Force the commutation step, e.g. when handing over from an open-loop
+    /// start.
+    pub fn set_step(&mut self, step: u8) {
+        self.step = step % 6;
+        self.time_in_step = I16F16::ZERO;
+        self.crossing_time = None;
+    }
+
+    /// The current commutation step, from 0 to 5.
+    pub fn step(&self) -> u8 {
+        self.step
+    }
+
+    /// The time since the last commutation in seconds, e.g. to detect a
+    /// stalled motor.
+    pub fn time_in_step(&self) -> I16F16 {
+        self.time_in_step
+    }
+
+    /// The electrical velocity in rad/s, from the duration of the last step.
+    pub fn velocity(&self) -> I16F16 {
+        match self.step_time {
+            Some(step_time) if step_time > I16F16::ZERO => STEP_ANGLE.saturating_div(step_time),
+            _ => I16F16::ZERO,
+        }
+    }
+
+    /// Update the commutator with the measured voltage of the floating phase
+    /// and the neutral voltage (e.g. half the bus voltage), in volts.
+    ///
+    /// `dt` is the time since the last update in seconds. Returns the
+    /// commutation step to apply.
+    pub fn update(&mut self, floating_voltage: I16F16, neutral_voltage: I16F16, dt: I16F16) -> u8 {
+        self.time_in_step = self.time_in_step.saturating_add(dt);
+
+        match self.crossing_time {
+            None if self.time_in_step > self.blanking_time => {
+                // The floating phase was low in the previous step for even
+                // steps, so its back-EMF is rising
+                let back_emf = floating_voltage.saturating_sub(neutral_voltage);
+                // `is_multiple_of` would need Rust 1.87
+                #[allow(clippy::manual_is_multiple_of)]
+                let crossed = if self.step % 2 == 0 {
+                    !back_emf.is_negative()
+                } else {
+                    !back_emf.is_positive()
+                };
+                if crossed {
+                    self.crossing_time = Some(self.time_in_step);
+                }
+            }
+            Some(crossing_time) if self.time_in_step >= crossing_time.saturating_mul_int(2) => {
+                self.step_time = Some(self.time_in_step);
+                self.step = (self.step + 1) % 6;
+                self.time_in_step = I16F16::ZERO;
+                self.crossing_time = None;
+            }
+            _ => {}
+        }
+
+        self.step
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::park_clarke;
+
+    #[test]
+    fn vector_leads_angle() {
+        for step in 0..36 {
+            let angle = I16F16::from_num(step as f32 * core::f32::consts::TAU / 36. + 0.01);
+            let commutation = Commutation::from_angle(angle, I16F16::lit("0.5"));
+
+            // The voltage vector of the step
+            let voltage = commutation.phases.map(|phase| match phase {
+                PhaseState::High => I16F16::ONE,
+                PhaseState::Low => -I16F16::ONE,
+                PhaseState::Floating => I16F16::ZERO,
+            });
+            let vector = park_clarke::clarke(park_clarke::ThreePhaseBalancedReferenceFrame {
+                a: voltage[0],
+                b: voltage[1],
+            });
+            let vector_angle = angle::atan2(vector.beta, vector.alpha);
+
+            let lead = angle::difference(vector_angle, angle);
+            assert!(
+                lead.abs_diff(I16F16::FRAC_PI_2) <= I16F16::FRAC_PI_6 + I16F16::lit("0.01"),
+                "lead {lead} at {angle}"
+            );
+        }
+    }
+
+    #[test]
+    fn negative_duty_reverses() {
+        let forward = Commutation::from_step(1, I16F16::lit("0.25"));
+        let reverse = Commutation::from_step(1, I16F16::lit("-0.25"));
+        assert_eq!(
+            forward.phases,
+            [PhaseState::Floating, PhaseState::High, PhaseState::Low]
+        );
+        assert_eq!(
+            reverse.phases,
+            [PhaseState::Floating, PhaseState::Low, PhaseState::High]
+        );
+        assert_eq!(reverse.duty, 0.25);
+        assert_eq!(reverse.as_compare_value::<1000>(), [0, 0, 250]);
+        assert_eq!(reverse.floating_phase(), 0);
+    }
+
+    #[test]
+    fn commutates_after_zero_crossing() {
+        let dt = I16F16::from_num(1. / 16384.);
+        let mut commutator = ZeroCrossingCommutator::new(0, dt * 4);
+
+        // Each step lasts 40 updates, with the crossing halfway
+        for step in 0..6u8 {
+            let rising = step % 2 == 0;
+            for update in 0..40 {
+                assert_eq!(commutator.step(), step);
+                let back_emf = I16F16::from_num(update - 19) / 10;
+                let back_emf = if rising { back_emf } else { -back_emf };
+                commutator.update(I16F16::from_num(6) + back_emf, I16F16::from_num(6), dt);
+            }
+        }
+        assert_eq!(commutator.step(), 0);
+        assert!(commutator.velocity().abs_diff(STEP_ANGLE / (dt * 40)) < 1);
+    }
+}
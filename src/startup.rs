@@ -0,0 +1,323 @@
+//! Open-loop start-up for sensorless control.
+//!
+//! Observers which estimate the angle from the back-EMF (see [`observer`])
+//! cannot do so at standstill. An [`OpenLoopStartup`] instead ramps up the
+//! velocity of an open-loop angle while applying a fixed current (I/f) or a
+//! voltage proportional to the velocity (V/f), which drags the rotor along
+//! behind it. Once the estimated velocity has matched the open-loop velocity
+//! for long enough, the angle is blended from the open-loop angle to the
+//! estimated angle, after which the estimate can be used directly.
+//!
+//! [`observer`]: crate::observer
+
+use fixed::types::I16F16;
+
+use crate::{angle, park_clarke::RotatingReferenceFrame, pwm, Foc};
+
+/// How the motor is excited while it is driven open-loop.
+#[derive(Debug, Clone)]
+pub enum Excitation {
+    /// I/f: the q-axis current in amps, applied with [`Foc::update`].
+    Current(I16F16),
+    /// V/f: the q-axis voltage in volts is `boost + volts_per_velocity * |ω|`
+    /// for electrical velocity `ω` in rad/s, applied with
+    /// [`Foc::update_voltage`].
+    Voltage {
+        /// Voltage at standstill to overcome the resistance, in volts.
+        boost: I16F16,
+        /// Voltage per electrical velocity, in V·s/rad.
+        volts_per_velocity: I16F16,
+    },
+}
+
+/// The phase of the start-up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// The open-loop velocity is ramping up, or waiting for the estimate to
+    /// lock.
+    Ramp,
+    /// The angle is moving from the open-loop angle to the estimated angle.
+    Blend,
+    /// The angle is the estimated angle.
+    ClosedLoop,
+}
+
+/// Generates an open-loop angle to start a motor, and hands over to an
+/// estimated angle.
+#[derive(Debug, Clone)]
+pub struct OpenLoopStartup {
+    excitation: Excitation,
+    acceleration: I16F16,
+    target_velocity: I16F16,
+    lock_tolerance: I16F16,
+    lock_time: I16F16,
+    blend_time: I16F16,
+    phase: Phase,
+    open_loop_angle: I16F16,
+    velocity: I16F16,
+    locked_time: I16F16,
+    blend: I16F16,
+    angle: I16F16,
+}
+
+impl OpenLoopStartup {
+    /// Create a new start-up from standstill at an angle of zero.
+    ///
+    /// Params:
+    /// - `excitation`: the current or voltage to apply
+    /// - `acceleration`: electrical acceleration in rad/s²
+    /// - `target_velocity`: electrical velocity in rad/s to ramp up to, where
+    ///   a negative velocity starts the motor backwards
+    ///
+    /// By default, the estimate is considered locked once its velocity has
+    /// been within a quarter of the target velocity for 0.1 seconds, and the
+    /// blend to the estimated angle takes 0.1 seconds.
+    pub fn new(excitation: Excitation, acceleration: I16F16, target_velocity: I16F16) -> Self {
+        Self {
+            excitation,
+            acceleration: acceleration.saturating_abs(),
+            target_velocity,
+            lock_tolerance: target_velocity.saturating_abs() / 4,
+            lock_time: I16F16::lit("0.1"),
+            blend_time: I16F16::lit("0.1"),
+            phase: Phase::Ramp,
+            open_loop_angle: I16F16::ZERO,
+            velocity: I16F16::ZERO,
+            locked_time: I16F16::ZERO,
+            blend: I16F16::ZERO,
+            angle: I16F16::ZERO,
+        }
+    }
+
+    /// Consider the estimate locked once the open-loop velocity has reached
+    /// the target, and the estimated velocity has been within `tolerance`
+    /// rad/s of it for `time` seconds.
+    pub fn with_lock_detection(mut self, tolerance: I16F16, time: I16F16) -> Self {
+        self.lock_tolerance = tolerance;
+        self.lock_time = time;
+        self
+    }
+
+    /// Set the time in seconds to blend from the open-loop angle to the
+    /// estimated angle.
+    pub fn with_blend_time(mut self, time: I16F16) -> Self {
+        self.blend_time = time;
+        self
+    }
+
+    /// Restart from standstill at the given electrical angle in radians, e.g.
+    /// the angle the rotor was aligned to.
+    pub fn restart(&mut self, electrical_angle: I16F16) {
+        self.phase = Phase::Ramp;
+        self.open_loop_angle = angle::wrap(electrical_angle);
+        self.velocity = I16F16::ZERO;
+        self.locked_time = I16F16::ZERO;
+        self.blend = I16F16::ZERO;
+        self.angle = self.open_loop_angle;
+    }
+
+    /// The phase of the start-up.
+    pub fn phase(&self) -> Phase {
+        self.phase
+    }
+
+    /// Whether the hand-over to the estimated angle has finished, after which
+    /// the motor can be controlled normally.
+    pub fn is_closed_loop(&self) -> bool {
+        self.phase == Phase::ClosedLoop
+    }
+
+    /// The electrical angle in radians to control with, in `[0, 2π)`.
+    pub fn angle(&self) -> I16F16 {
+        self.angle
+    }
+
+    /// The open-loop electrical velocity in rad/s.
+    pub fn velocity(&self) -> I16F16 {
+        self.velocity
+    }
+
+    /// Update the start-up with the estimated electrical angle in radians and
+    /// velocity in rad/s, e.g. from an observer.
+    ///
+    /// `dt` is the time since the last update in seconds. Returns the
+    /// electrical angle in radians to control with, in `[0, 2π)`.
+    pub fn update(
+        &mut self,
+        estimated_angle: I16F16,
+        estimated_velocity: I16F16,
+        dt: I16F16,
+    ) -> I16F16 {
+        // Ramp the open-loop velocity towards the target
+        let step = self.acceleration.saturating_mul(dt);
+        self.velocity = self.target_velocity.clamp(
+            self.velocity.saturating_sub(step),
+            self.velocity.saturating_add(step),
+        );
+        self.open_loop_angle = angle::wrap(self.open_loop_angle + self.velocity.saturating_mul(dt));
+
+        match self.phase {
+            Phase::Ramp => {
+                let locked = self.velocity == self.target_velocity
+                    && estimated_velocity
+                        .saturating_sub(self.velocity)
+                        .saturating_abs()
+                        <= self.lock_tolerance;
+                self.locked_time = if locked {
+                    self.locked_time.saturating_add(dt)
+                } else {
+                    I16F16::ZERO
+                };
+                if locked && self.locked_time >= self.lock_time {
+                    self.phase = Phase::Blend;
+                }
+                self.angle = self.open_loop_angle;
+            }
+            Phase::Blend => {
+                self.blend = if self.blend_time > I16F16::ZERO {
+                    self.blend.saturating_add(dt / self.blend_time)
+                } else {
+                    I16F16::ONE
+                };
+                if self.blend >= I16F16::ONE {
+                    self.phase = Phase::ClosedLoop;
+                    self.angle = angle::wrap(estimated_angle);
+                } else {
+                    let error = angle::difference(estimated_angle, self.open_loop_angle);
+                    self.angle =
+                        angle::wrap(self.open_loop_angle + self.blend.saturating_mul(error));
+                }
+            }
+            Phase::ClosedLoop => self.angle = angle::wrap(estimated_angle),
+        }
+
+        self.angle
+    }
+
+    /// Drive the controller with the excitation at the current angle.
+    ///
+    /// Params:
+    /// - `foc`: the controller to update
+    /// - `currents`: phase currents in amps
+    /// - `dt`: time delta since last update, in seconds
+    ///
+    /// Returns:
+    /// - The 3 PWM values to be set on your timer channels.
+    pub fn apply<Modulator: pwm::Modulation, const PWM_RESOLUTION: u16>(
+        &self,
+        foc: &mut Foc<Modulator, PWM_RESOLUTION>,
+        currents: [I16F16; 2],
+        dt: I16F16,
+    ) -> [u16; 3] {
+        match &self.excitation {
+            Excitation::Current(current) => {
+                let current = if self.target_velocity.is_negative() {
+                    -current.saturating_abs()
+                } else {
+                    current.saturating_abs()
+                };
+                foc.update(currents, self.angle, self.velocity, current, dt)
+            }
+            Excitation::Voltage {
+                boost,
+                volts_per_velocity,
+            } => {
+                let voltage = boost.saturating_add(
+                    volts_per_velocity.saturating_mul(self.velocity.saturating_abs()),
+                );
+                let voltage = if self.target_velocity.is_negative() {
+                    -voltage
+                } else {
+                    voltage
+                };
+                foc.update_voltage(
+                    currents,
+                    self.angle,
+                    self.velocity,
+                    RotatingReferenceFrame {
+                        d: I16F16::ZERO,
+                        q: voltage,
+                    },
+                )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pid::PIController;
+
+    #[test]
+    fn ramps_locks_and_blends() {
+        let mut startup = OpenLoopStartup::new(
+            Excitation::Current(I16F16::from_num(5)),
+            I16F16::from_num(1000),
+            I16F16::from_num(-200),
+        );
+        let dt = I16F16::from_num(1. / 16384.);
+
+        // The estimate lags the open-loop angle by 0.5 rad, and only has the
+        // right velocity once the ramp has finished
+        let mut last_angle = startup.angle();
+        let mut blend_updates = 0;
+        for update in 0..16384 {
+            let (estimated_angle, estimated_velocity) = if startup.velocity() == -200 {
+                (
+                    startup.open_loop_angle + I16F16::lit("0.5"),
+                    startup.velocity(),
+                )
+            } else {
+                (I16F16::ZERO, I16F16::ZERO)
+            };
+            let angle = startup.update(estimated_angle, estimated_velocity, dt);
+
+            // The ramp takes 0.2 s, and the lock 0.1 s
+            if update < 4915 {
+                assert_eq!(startup.phase(), Phase::Ramp);
+            }
+            if startup.phase() == Phase::Blend {
+                blend_updates += 1;
+            }
+
+            // No step larger than the velocity step plus the blend rate
+            let step = angle::difference(angle, last_angle).abs();
+            assert!(step < 0.013, "step {step} at {update}");
+            last_angle = angle;
+        }
+        assert!(startup.is_closed_loop());
+        assert!((1600..1700).contains(&blend_updates), "{blend_updates}");
+        // The estimate was from before the last step of the open-loop angle
+        let error = angle::difference(startup.angle(), startup.open_loop_angle);
+        assert!(error.abs_diff(I16F16::lit("0.5")) < 0.02);
+    }
+
+    #[test]
+    fn applies_voltage() {
+        let controller = || PIController::new(I16F16::ONE, I16F16::ZERO);
+        let mut foc: Foc<pwm::SpaceVector, 1000> =
+            Foc::new(controller(), controller(), I16F16::from_num(24));
+        let excitation = Excitation::Voltage {
+            boost: I16F16::ONE,
+            volts_per_velocity: I16F16::lit("0.01"),
+        };
+        let mut startup =
+            OpenLoopStartup::new(excitation, I16F16::from_num(100), I16F16::from_num(100));
+        let dt = I16F16::lit("0.01");
+
+        for _ in 0..200 {
+            startup.update(I16F16::ZERO, I16F16::ZERO, dt);
+        }
+        assert_eq!(startup.velocity(), 100);
+        startup.apply(&mut foc, [I16F16::ZERO; 2], dt);
+
+        // The voltage is on the q-axis of the open-loop angle
+        let voltage = foc.orthogonal_voltage();
+        let (sin, cos) = cordic::sin_cos(startup.angle());
+        let expected_alpha = -sin * 2;
+        let expected_beta = cos * 2;
+        assert!(voltage.alpha.abs_diff(expected_alpha) < 0.01);
+        assert!(voltage.beta.abs_diff(expected_beta) < 0.01);
+    }
+}
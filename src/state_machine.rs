@@ -0,0 +1,593 @@
+//! A state machine which manages the lifecycle of a [`Foc`] controller.
+//!
+//! The controller starts in [`State::Idle`] with the outputs disabled. Once
+//! started, it calibrates the current sensor offsets, optionally aligns the
+//! rotor to measure the electrical offset of the angle sensor, optionally
+//! drives the motor open-loop until the angle estimate has locked, and then
+//! runs the current loops. Any fault disables the outputs until it is
+//! cleared.
+//!
+//! ```text
+//! Idle --start--> Calibrating --> Aligning --> Starting --> Running
+//!  ^                   |              |            |           |
+//!  |                   v              v            v           v
+//!  +--clear_fault--- Fault <----------+------------+-----------+
+//! ```
+
+use fixed::types::I16F16;
+
+use crate::{
+    angle,
+    current_sense::{ConvertCurrents, CurrentSense, OffsetCalibration},
+    protection::{self, Measurements, Protection},
+    pwm,
+    startup::OpenLoopStartup,
+    Foc,
+};
+
+/// The reason that the state machine entered [`State::Fault`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// The calibrated zero-current offset of a current sensor channel was too
+    /// far from its nominal value.
+    OffsetCalibration { channel: usize },
+    /// A fault detected by the [`Protection`] while the outputs were enabled.
+    Protection(protection::Fault),
+    /// A fault reported with [`StateMachine::trigger_fault`], e.g. from a gate
+    /// driver fault pin.
+    External,
+}
+
+/// The state of a [`StateMachine`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    /// The outputs are disabled, waiting for [`StateMachine::start`].
+    Idle,
+    /// The outputs are disabled while the current sensor offsets are measured.
+    Calibrating,
+    /// A current is applied at an electrical angle of zero to pull the rotor
+    /// into alignment.
+    Aligning,
+    /// The motor is driven open-loop by an [`OpenLoopStartup`] until the angle
+    /// estimate has locked.
+    Starting,
+    /// The current loops are running.
+    Running,
+    /// The outputs are disabled until [`StateMachine::clear_fault`] is called.
+    Fault(Fault),
+}
+
+/// The output of a single [`StateMachine::tick`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Output {
+    /// The outputs of the bridge should be disabled (all switches off).
+    Disabled,
+    /// The PWM compare values to set on the timer channels.
+    Pwm([u16; 3]),
+}
+
+/// A state machine around a [`Foc`] controller, with `CHANNELS` current sensor
+/// channels (2 or 3).
+pub struct StateMachine<
+    Modulator: pwm::Modulation,
+    const PWM_RESOLUTION: u16,
+    const CHANNELS: usize,
+> {
+    foc: Foc<Modulator, PWM_RESOLUTION>,
+    current_sense: CurrentSense<CHANNELS>,
+    calibration_samples: u16,
+    calibration: OffsetCalibration<CHANNELS>,
+    nominal_offsets: [u16; CHANNELS],
+    offset_tolerance: u16,
+    alignment: Option<(I16F16, I16F16)>,
+    startup: Option<OpenLoopStartup>,
+    protection: Option<Protection>,
+    fet_temperature: Option<I16F16>,
+    motor_temperature: Option<I16F16>,
+    state: State,
+    time_in_state: I16F16,
+    electrical_offset: I16F16,
+    torque: I16F16,
+}
+
+impl<Modulator: pwm::Modulation, const PWM_RESOLUTION: u16, const CHANNELS: usize>
+    StateMachine<Modulator, PWM_RESOLUTION, CHANNELS>
+where
+    CurrentSense<CHANNELS>: ConvertCurrents<CHANNELS>,
+{
+    /// Create a new state machine in [`State::Idle`].
+    ///
+    /// The current sensor offsets are calibrated by averaging
+    /// `calibration_samples` samples each time the state machine is started.
+    pub fn new(
+        foc: Foc<Modulator, PWM_RESOLUTION>,
+        current_sense: CurrentSense<CHANNELS>,
+        calibration_samples: u16,
+    ) -> Self {
+        Self {
+            foc,
+            nominal_offsets: current_sense.offsets(),
+            current_sense,
+            calibration_samples,
+            calibration: OffsetCalibration::new(calibration_samples),
+            offset_tolerance: u16::MAX,
+            alignment: None,
+            startup: None,
+            protection: None,
+            fet_temperature: None,
+            motor_temperature: None,
+            state: State::Idle,
+            time_in_state: I16F16::ZERO,
+            electrical_offset: I16F16::ZERO,
+            torque: I16F16::ZERO,
+        }
+    }
+
+    /// Enter [`State::Fault`] if a calibrated offset differs from the offset
+    /// of the current sense when it was passed to
+    /// [`StateMachine::new`] by more than `tolerance` ADC counts.
+    pub fn with_offset_tolerance(mut self, tolerance: u16) -> Self {
+        self.offset_tolerance = tolerance;
+        self
+    }
+
+    /// Align the rotor after calibrating by applying `current` amps at an
+    /// electrical angle of zero for `duration` seconds.
+    ///
+    /// Without a start-up, the angle measured at the end of the alignment is
+    /// used as the electrical offset of the angle sensor. With a start-up (see
+    /// [`StateMachine::with_startup`]) the angle is an estimate which means
+    /// nothing at standstill, so the alignment only pulls the rotor to where
+    /// the start-up begins. Without alignment, the angle is assumed to already
+    /// be relative to the rotor.
+    pub fn with_alignment(mut self, current: I16F16, duration: I16F16) -> Self {
+        self.alignment = Some((current, duration));
+        self
+    }
+
+    /// Start the motor open-loop with `startup` after calibrating and
+    /// aligning, e.g. for sensorless control.
+    ///
+    /// The start-up is restarted from an electrical angle of zero, which is
+    /// where the rotor is pulled to by the alignment. The angle passed to
+    /// [`StateMachine::tick`] is used as the estimated angle, and the state
+    /// machine enters [`State::Running`] once the start-up has handed over to
+    /// it. The estimated angle is not corrected by an electrical offset.
+    pub fn with_startup(mut self, startup: OpenLoopStartup) -> Self {
+        self.startup = Some(startup);
+        self
+    }
+
+    /// The open-loop start-up, if any.
+    pub fn startup(&self) -> Option<&OpenLoopStartup> {
+        self.startup.as_ref()
+    }
+
+    /// Check the measurements with the given protection while the outputs are
+    /// enabled, entering [`State::Fault`] if it reports a fault.
+    pub fn with_protection(mut self, protection: Protection) -> Self {
+        self.protection = Some(protection);
+        self
+    }
+
+    /// Set the latest temperatures of the power stage and motor in °C, for
+    /// the protection checks.
+    pub fn set_temperatures(&mut self, fet: Option<I16F16>, motor: Option<I16F16>) {
+        self.fet_temperature = fet;
+        self.motor_temperature = motor;
+    }
+
+    /// The protection, e.g. to read the RMS current.
+    pub fn protection(&self) -> Option<&Protection> {
+        self.protection.as_ref()
+    }
+
+    /// The current state.
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// The underlying FOC controller.
+    pub fn foc(&self) -> &Foc<Modulator, PWM_RESOLUTION> {
+        &self.foc
+    }
+
+    /// The underlying FOC controller, e.g. to update the bus voltage.
+    pub fn foc_mut(&mut self) -> &mut Foc<Modulator, PWM_RESOLUTION> {
+        &mut self.foc
+    }
+
+    /// The current sense, including the calibrated offsets.
+    pub fn current_sense(&self) -> &CurrentSense<CHANNELS> {
+        &self.current_sense
+    }
+
+    /// The electrical offset in radians measured during alignment, which is
+    /// subtracted from the angle while running.
+    pub fn electrical_offset(&self) -> I16F16 {
+        self.electrical_offset
+    }
+
+    /// Set the desired q-axis current in amps while running.
+    pub fn set_torque(&mut self, torque: I16F16) {
+        self.torque = torque;
+    }
+
+    /// The desired q-axis current in amps.
+    pub fn torque(&self) -> I16F16 {
+        self.torque
+    }
+
+    /// Start calibrating, then aligning, starting and running. Only has an
+    /// effect in
+    /// [`State::Idle`].
+    pub fn start(&mut self) {
+        if self.state == State::Idle {
+            self.calibration = OffsetCalibration::new(self.calibration_samples);
+            if let Some(protection) = &mut self.protection {
+                protection.reset();
+            }
+            self.transition(State::Calibrating);
+        }
+    }
+
+    /// Disable the outputs and return to [`State::Idle`]. Has no effect in
+    /// [`State::Fault`].
+    pub fn stop(&mut self) {
+        if !matches!(self.state, State::Fault(_)) {
+            self.transition(State::Idle);
+        }
+    }
+
+    /// Disable the outputs and enter [`State::Fault`].
+    ///
+    /// The first fault is kept until it is cleared.
+    pub fn trigger_fault(&mut self, fault: Fault) {
+        if !matches!(self.state, State::Fault(_)) {
+            self.transition(State::Fault(fault));
+        }
+    }
+
+    /// Clear a fault and return to [`State::Idle`], returning the fault that
+    /// was cleared, if any.
+    pub fn clear_fault(&mut self) -> Option<Fault> {
+        match self.state {
+            State::Fault(fault) => {
+                self.transition(State::Idle);
+                Some(fault)
+            }
+            _ => None,
+        }
+    }
+
+    fn transition(&mut self, state: State) {
+        self.state = state;
+        self.time_in_state = I16F16::ZERO;
+        self.foc.reset();
+    }
+
+    /// Run the state machine once, with the raw current sensor readings.
+    ///
+    /// Params:
+    /// - `raw_currents`: raw ADC readings of each current sensor channel
+    /// - `angle`: electrical angle in radians
+    /// - `velocity`: electrical velocity in rad/s
+    /// - `dt`: time delta since last tick in seconds
+    pub fn tick(
+        &mut self,
+        raw_currents: [u16; CHANNELS],
+        angle: I16F16,
+        velocity: I16F16,
+        dt: I16F16,
+    ) -> Output {
+        self.time_in_state = self.time_in_state.saturating_add(dt);
+
+        match self.state {
+            State::Idle | State::Fault(_) => Output::Disabled,
+            State::Calibrating => {
+                if let Some(offsets) = self.calibration.add_sample(raw_currents) {
+                    let out_of_range =
+                        offsets
+                            .iter()
+                            .zip(self.nominal_offsets)
+                            .position(|(&offset, nominal)| {
+                                offset.abs_diff(nominal) > self.offset_tolerance
+                            });
+                    if let Some(channel) = out_of_range {
+                        self.transition(State::Fault(Fault::OffsetCalibration { channel }));
+                    } else {
+                        self.current_sense.set_offsets(offsets);
+                        match self.alignment {
+                            Some(_) => self.transition(State::Aligning),
+                            None => self.start_running(),
+                        }
+                    }
+                }
+                Output::Disabled
+            }
+            State::Aligning | State::Starting | State::Running => {
+                let balanced = self.current_sense.convert(raw_currents);
+                let currents = [balanced.a, balanced.b];
+                if let Some(protection) = &mut self.protection {
+                    let measurements = Measurements {
+                        currents,
+                        bus_voltage: self.foc.bus_voltage(),
+                        fet_temperature: self.fet_temperature,
+                        motor_temperature: self.motor_temperature,
+                        velocity,
+                    };
+                    if let Err(fault) = protection.update(&measurements, dt) {
+                        self.transition(State::Fault(Fault::Protection(fault)));
+                        return Output::Disabled;
+                    }
+                }
+
+                let corrected = angle::wrap(angle.wrapping_sub(self.electrical_offset));
+                match (self.state, &mut self.startup) {
+                    (State::Starting, Some(startup)) => {
+                        startup.update(corrected, velocity, dt);
+                        let pwm = startup.apply(&mut self.foc, currents, dt);
+                        if startup.is_closed_loop() {
+                            // Keep the state of the current controllers, so
+                            // that the hand-over does not step the output
+                            self.state = State::Running;
+                            self.time_in_state = I16F16::ZERO;
+                        }
+                        Output::Pwm(pwm)
+                    }
+                    (State::Aligning, _) => {
+                        let (current, duration) = self.alignment.unwrap_or_default();
+                        // The q-axis points along an electrical angle of zero
+                        // when the d-axis is at -π/2
+                        let pwm = self.foc.update(
+                            currents,
+                            I16F16::PI + I16F16::FRAC_PI_2,
+                            I16F16::ZERO,
+                            current,
+                            dt,
+                        );
+                        if self.time_in_state >= duration {
+                            // An estimated angle is already relative to the
+                            // rotor
+                            if self.startup.is_none() {
+                                self.electrical_offset = angle::wrap(angle);
+                            }
+                            self.start_running();
+                        }
+                        Output::Pwm(pwm)
+                    }
+                    _ => {
+                        Output::Pwm(
+                            self.foc
+                                .update(currents, corrected, velocity, self.torque, dt),
+                        )
+                    }
+                }
+            }
+        }
+    }
+
+    /// Enter [`State::Starting`] if there is a start-up, otherwise
+    /// [`State::Running`].
+    fn start_running(&mut self) {
+        match &mut self.startup {
+            Some(startup) => {
+                startup.restart(I16F16::ZERO);
+                self.transition(State::Starting);
+            }
+            None => self.transition(State::Running),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{current_sense::AdcConversion, pid::PIController, startup::Excitation};
+
+    fn state_machine() -> StateMachine<pwm::SpaceVector, 1000, 2> {
+        let controller = || PIController::new(I16F16::ONE, I16F16::ZERO);
+        let foc = Foc::new(controller(), controller(), I16F16::from_num(24));
+        let sense = CurrentSense::<2>::new(AdcConversion::new(I16F16::from_num(64), 12));
+        StateMachine::new(foc, sense, 4).with_offset_tolerance(100)
+    }
+
+    #[test]
+    fn calibrates_aligns_and_runs() {
+        let dt = I16F16::lit("0.25");
+        let mut machine = state_machine().with_alignment(I16F16::from_num(2), I16F16::ONE);
+        assert_eq!(
+            machine.tick([2048; 2], I16F16::ZERO, I16F16::ZERO, dt),
+            Output::Disabled
+        );
+
+        machine.start();
+        for _ in 0..4 {
+            assert_eq!(machine.state(), State::Calibrating);
+            let output = machine.tick([2050, 2040], I16F16::ZERO, I16F16::ZERO, dt);
+            assert_eq!(output, Output::Disabled);
+        }
+        assert_eq!(machine.current_sense().offsets(), [2050, 2040]);
+
+        for _ in 0..4 {
+            assert_eq!(machine.state(), State::Aligning);
+            let output = machine.tick([2050, 2040], I16F16::ONE, I16F16::ZERO, dt);
+            assert!(matches!(output, Output::Pwm(_)));
+        }
+        assert_eq!(machine.state(), State::Running);
+        assert_eq!(machine.electrical_offset(), I16F16::ONE);
+
+        machine.stop();
+        assert_eq!(machine.state(), State::Idle);
+    }
+
+    #[test]
+    fn latches_faults() {
+        let dt = I16F16::lit("0.25");
+        let mut machine = state_machine();
+
+        machine.start();
+        for _ in 0..4 {
+            machine.tick([2048, 2200], I16F16::ZERO, I16F16::ZERO, dt);
+        }
+        let fault = Fault::OffsetCalibration { channel: 1 };
+        assert_eq!(machine.state(), State::Fault(fault));
+
+        // Faults are not overwritten, and cannot be left without clearing
+        machine.trigger_fault(Fault::External);
+        machine.stop();
+        machine.start();
+        assert_eq!(machine.state(), State::Fault(fault));
+        assert_eq!(
+            machine.tick([2048; 2], I16F16::ZERO, I16F16::ZERO, dt),
+            Output::Disabled
+        );
+
+        assert_eq!(machine.clear_fault(), Some(fault));
+        assert_eq!(machine.state(), State::Idle);
+        assert_eq!(machine.clear_fault(), None);
+    }
+
+    #[test]
+    fn protection_faults_while_running() {
+        let dt = I16F16::lit("0.25");
+        let mut machine = state_machine()
+            .with_protection(Protection::new().with_overcurrent(I16F16::from_num(10), 2));
+
+        machine.start();
+        for _ in 0..4 {
+            machine.tick([2048; 2], I16F16::ZERO, I16F16::ZERO, dt);
+        }
+        assert_eq!(machine.state(), State::Running);
+
+        // 12 A in phase A
+        let raw = [2048 + 12 * 64, 2048];
+        assert!(matches!(
+            machine.tick(raw, I16F16::ZERO, I16F16::ZERO, dt),
+            Output::Pwm(_)
+        ));
+        assert_eq!(
+            machine.tick(raw, I16F16::ZERO, I16F16::ZERO, dt),
+            Output::Disabled
+        );
+        assert_eq!(
+            machine.state(),
+            State::Fault(Fault::Protection(protection::Fault::Overcurrent))
+        );
+    }
+
+    #[test]
+    fn starts_open_loop_and_hands_over() {
+        let dt = I16F16::lit("0.01");
+        let startup = OpenLoopStartup::new(
+            Excitation::Current(I16F16::from_num(2)),
+            I16F16::from_num(100),
+            I16F16::from_num(10),
+        );
+        let mut machine = state_machine().with_startup(startup);
+
+        machine.start();
+        for _ in 0..4 {
+            machine.tick([2048; 2], I16F16::ZERO, I16F16::ZERO, dt);
+        }
+        assert_eq!(machine.state(), State::Starting);
+
+        // The estimate does not lock while the motor is not moving
+        for _ in 0..100 {
+            let output = machine.tick([2048; 2], I16F16::ZERO, I16F16::ZERO, dt);
+            assert!(matches!(output, Output::Pwm(_)));
+            assert_eq!(machine.state(), State::Starting);
+        }
+        let startup = machine.startup().unwrap();
+        assert_eq!(startup.velocity(), 10);
+
+        // Locking takes 0.1 s, and blending another 0.1 s
+        let mut ticks = 0;
+        while machine.state() == State::Starting {
+            let angle = machine.startup().unwrap().angle();
+            machine.tick([2048; 2], angle, I16F16::from_num(10), dt);
+            ticks += 1;
+            assert!(ticks < 25);
+        }
+        assert!(ticks > 15);
+        assert_eq!(machine.state(), State::Running);
+
+        // Starting again restarts the ramp from standstill
+        machine.stop();
+        machine.start();
+        for _ in 0..5 {
+            machine.tick([2048; 2], I16F16::ZERO, I16F16::ZERO, dt);
+        }
+        assert_eq!(machine.state(), State::Starting);
+        assert!(machine.startup().unwrap().velocity().abs_diff(I16F16::ONE) < 0.01);
+    }
+
+    #[test]
+    fn removes_common_mode_with_three_channels() {
+        let dt = I16F16::lit("0.25");
+        let controller = || PIController::new(I16F16::ONE, I16F16::ZERO);
+        let foc: Foc<pwm::SpaceVector, 1000> =
+            Foc::new(controller(), controller(), I16F16::from_num(24));
+        let sense = CurrentSense::<3>::new(AdcConversion::new(I16F16::from_num(64), 12));
+        let mut machine = StateMachine::new(foc, sense, 4)
+            .with_protection(Protection::new().with_overcurrent(I16F16::from_num(2), 1));
+
+        machine.start();
+        for _ in 0..4 {
+            machine.tick([2048; 3], I16F16::ZERO, I16F16::ZERO, dt);
+        }
+        assert_eq!(machine.state(), State::Running);
+
+        // 3 A on every channel is common-mode error, not phase current
+        let raw = [2048 + 3 * 64; 3];
+        machine.tick(raw, I16F16::ZERO, I16F16::ZERO, dt);
+        assert_eq!(machine.state(), State::Running);
+    }
+
+    #[test]
+    fn aligns_and_starts_with_observer_angle() {
+        let dt = I16F16::lit("0.01");
+        let startup = OpenLoopStartup::new(
+            Excitation::Current(I16F16::from_num(2)),
+            I16F16::from_num(100),
+            I16F16::from_num(10),
+        );
+        let mut machine = state_machine()
+            .with_alignment(I16F16::from_num(2), I16F16::lit("0.1"))
+            .with_startup(startup);
+
+        machine.start();
+        for _ in 0..4 {
+            machine.tick([2048; 2], I16F16::ZERO, I16F16::ZERO, dt);
+        }
+
+        // The observer angle is meaningless while the rotor is aligned, and
+        // is not used as an offset
+        let observer_angle = I16F16::lit("1.5");
+        let mut ticks = 0;
+        while machine.state() == State::Aligning {
+            machine.tick([2048; 2], observer_angle, I16F16::ZERO, dt);
+            ticks += 1;
+            assert!(ticks < 20);
+        }
+        assert_eq!(machine.state(), State::Starting);
+        assert_eq!(machine.electrical_offset(), I16F16::ZERO);
+
+        // The observer locks on to the open-loop angle
+        while machine.state() == State::Starting {
+            let angle = machine.startup().unwrap().angle();
+            machine.tick([2048; 2], angle, I16F16::from_num(10), dt);
+            ticks += 1;
+            assert!(ticks < 200);
+        }
+        assert_eq!(machine.state(), State::Running);
+
+        // The torque is applied on the q-axis of the observer angle
+        machine.set_torque(I16F16::ONE);
+        machine.tick([2048; 2], observer_angle, I16F16::ZERO, dt);
+        let voltage = machine.foc().orthogonal_voltage();
+        let (sin, cos) = cordic::sin_cos(observer_angle);
+        assert!(voltage.alpha.abs_diff(sin) < 0.01, "{voltage:?}");
+        assert!(voltage.beta.abs_diff(-cos) < 0.01, "{voltage:?}");
+    }
+}
@@ -0,0 +1,173 @@
+//! I²t thermal model for current derating.
+//!
+//! The heating of a motor or inverter is proportional to the square of the
+//! current, and it cools exponentially towards ambient. A [`ThermalModel`]
+//! tracks the resulting temperature rise as a fraction of the allowed rise,
+//! and derates the current limit as it approaches that limit. The limit can be
+//! applied with [`Foc::set_current_limit`](crate::Foc::set_current_limit).
+
+use fixed::types::{I16F16, I32F32};
+
+use crate::park_clarke::RotatingReferenceFrame;
+
+/// A first-order thermal model, where the current can be above its continuous
+/// rating for short periods.
+///
+/// The state is the temperature rise as a fraction of the allowed rise, which
+/// settles to `(I / continuous_current)²` at a constant current `I`. The
+/// thermal time constant is usually long compared to the update period, so
+/// the state is kept in a higher precision internally.
+#[derive(Debug, Clone)]
+pub struct ThermalModel {
+    continuous_current: I16F16,
+    peak_current: I16F16,
+    time_constant: I32F32,
+    derating_start: I16F16,
+    state: I32F32,
+}
+
+impl ThermalModel {
+    /// Create a new thermal model, starting at ambient temperature.
+    ///
+    /// Params:
+    /// - `continuous_current`: current in amps which can be sustained
+    ///   indefinitely without exceeding the temperature limit, clamped to be
+    ///   positive
+    /// - `peak_current`: current limit in amps while cool
+    /// - `time_constant`: thermal time constant in seconds
+    pub fn new(continuous_current: I16F16, peak_current: I16F16, time_constant: I16F16) -> Self {
+        Self {
+            continuous_current: continuous_current.max(I16F16::DELTA),
+            peak_current,
+            time_constant: I32F32::from(time_constant),
+            derating_start: I16F16::lit("0.8"),
+            state: I32F32::ZERO,
+        }
+    }
+
+    /// Set the thermal state at which the current limit starts to be reduced
+    /// from the peak current, as a fraction of the temperature limit.
+    ///
+    /// The current limit reaches the continuous current at the temperature
+    /// limit. Defaults to 0.8.
+    pub fn with_derating_start(mut self, derating_start: I16F16) -> Self {
+        self.derating_start = derating_start.clamp(I16F16::ZERO, I16F16::ONE - I16F16::DELTA);
+        self
+    }
+
+    /// Set the thermal state, e.g. to restore an estimate after a restart.
+    pub fn set_thermal_state(&mut self, state: I16F16) {
+        self.state = I32F32::from(state.max(I16F16::ZERO));
+    }
+
+    /// The temperature rise as a fraction of the allowed temperature rise,
+    /// where 1 is at the temperature limit.
+    pub fn thermal_state(&self) -> I16F16 {
+        self.state.saturating_to_num()
+    }
+
+    /// The estimated temperature, given the ambient temperature and the
+    /// temperature limit.
+    pub fn temperature(&self, ambient: I16F16, limit: I16F16) -> I16F16 {
+        ambient.saturating_add(
+            limit
+                .saturating_sub(ambient)
+                .saturating_mul(self.thermal_state()),
+        )
+    }
+
+    /// Update the model with the measured d/q current in amps.
+    ///
+    /// `dt` is the time since the last update in seconds.
+    pub fn update(&mut self, current: &RotatingReferenceFrame, dt: I16F16) {
+        // Normalise before squaring to stay in range
+        let d = current.d.saturating_div(self.continuous_current);
+        let q = current.q.saturating_div(self.continuous_current);
+        let heating = I32F32::from(d.saturating_mul(d).saturating_add(q.saturating_mul(q)));
+
+        let alpha = if self.time_constant > I32F32::ZERO {
+            (I32F32::from(dt) / self.time_constant).min(I32F32::ONE)
+        } else {
+            I32F32::ONE
+        };
+        self.state = self
+            .state
+            .saturating_add(alpha.saturating_mul(heating.saturating_sub(self.state)));
+    }
+
+    /// The current limit in amps for the present thermal state.
+    ///
+    /// This is the peak current until the state reaches the derating start,
+    /// and then reduces linearly to the continuous current at the temperature
+    /// limit.
+    pub fn current_limit(&self) -> I16F16 {
+        let derating = self
+            .thermal_state()
+            .saturating_sub(self.derating_start)
+            .saturating_div(I16F16::ONE - self.derating_start)
+            .clamp(I16F16::ZERO, I16F16::ONE);
+        self.peak_current.saturating_sub(
+            self.peak_current
+                .saturating_sub(self.continuous_current)
+                .saturating_mul(derating),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn derates_to_continuous_current() {
+        let mut model = ThermalModel::new(
+            I16F16::from_num(10),
+            I16F16::from_num(30),
+            I16F16::from_num(20),
+        );
+        let dt = I16F16::from_num(1. / 1024.);
+
+        // Demand three times the continuous current
+        let mut derating_time = None;
+        for step in 0..(60 * 1024) {
+            let current = RotatingReferenceFrame {
+                d: I16F16::ZERO,
+                q: model.current_limit(),
+            };
+            model.update(&current, dt);
+            assert!(model.thermal_state() <= 1.01);
+
+            if derating_time.is_none() && model.current_limit() < 30 {
+                derating_time = Some(step as f32 / 1024.);
+            }
+        }
+
+        // 9 times the continuous heating reaches 0.8 after -τ·ln(1 - 0.8/9)
+        let expected = -20. * (1f32 - 0.8 / 9.).ln();
+        let derating_time = derating_time.unwrap();
+        assert!((derating_time - expected).abs() < 0.05, "{derating_time}");
+
+        assert!(model.current_limit().abs_diff(I16F16::from_num(10)) < 0.5);
+        assert!(
+            model
+                .temperature(I16F16::from_num(25), I16F16::from_num(125))
+                .abs_diff(I16F16::from_num(125))
+                < 2
+        );
+    }
+
+    #[test]
+    fn non_positive_continuous_current() {
+        let current = RotatingReferenceFrame {
+            d: I16F16::ZERO,
+            q: I16F16::ONE,
+        };
+        for continuous_current in [I16F16::ZERO, I16F16::from_num(-5)] {
+            let mut model =
+                ThermalModel::new(continuous_current, I16F16::from_num(30), I16F16::ONE);
+            model.update(&current, I16F16::ONE);
+            assert_eq!(model.thermal_state(), I16F16::MAX);
+            assert!(model.current_limit() < 0.001);
+        }
+    }
+}